@@ -1,6 +1,7 @@
 pub mod pic;
 pub mod pit;
 pub mod device;
+pub mod rtc;
 
 #[no_mangle]
 pub unsafe fn undefined_entry() -> ! {
@@ -42,8 +43,10 @@ pub fn wait() {
 
 #[inline(always)]
 pub fn enable_wait() {
-    enable();
+    // 割り込みが禁止されていてもwfiは割り込みで復帰するため、
+    // 先に待機することで割り込みを取りこぼさない
     wait();
+    enable();
 }
 
 #[inline(always)]
@@ -0,0 +1,29 @@
+// ARMの対応している機種にはリアルタイムクロックが無い
+
+use time::DateTime;
+
+/// 現在の日時を読み出す。リアルタイムクロックが無いので常に`None`を返す。
+#[inline(always)]
+pub fn read() -> Option<DateTime> {
+    None
+}
+
+/// 周期割り込みを設定する。リアルタイムクロックが無いので常に`false`を返す。
+#[inline(always)]
+pub fn set_periodic(_freq: u32) -> bool {
+    false
+}
+
+/// 周期割り込みを止める。
+#[inline(always)]
+pub fn stop_periodic() { }
+
+/// アラームを設定する。リアルタイムクロックが無いので常に`false`を返す。
+#[inline(always)]
+pub fn set_alarm(_hour: u8, _minute: u8, _second: u8) -> bool {
+    false
+}
+
+/// アラームを止める。
+#[inline(always)]
+pub fn clear_alarm() { }
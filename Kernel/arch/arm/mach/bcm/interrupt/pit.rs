@@ -3,6 +3,7 @@ use arch::page::PageTable;
 use super::super::PERIPHERAL_BASE;
 use super::pic;
 use timer;
+use core::cmp;
 use core::sync::atomic::{self, Ordering};
 
 const SYSTIMER_BASE: Register<u32> = PERIPHERAL_BASE.offset(0x3000);
@@ -23,6 +24,14 @@ const SYSTIMER_C2:  Register<u32> = SYSTIMER_BASE.offset(0x14);
 const SYSTIMER_C3:  Register<u32> = SYSTIMER_BASE.offset(0x18);
 
 const INTERVAL_MS: u32 = 10;
+// ワンショットで待機できる最大の時間
+const MAX_ONESHOT_MS: usize = 1000 * 1000;
+
+// 最後に経過時間を計上した時のカウンタの値
+static mut last_clock: u32 = 0;
+static mut oneshot: bool = false;
+// 起動時のカウンタの値
+static mut boot_clock: u64 = 0;
 
 #[repr(u8)]
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -78,17 +87,71 @@ pub unsafe fn init() {
     pic::IRQ::SysTimer1.set_handler(irq_handler);
     pic::IRQ::SysTimer1.enable();
 
-    Timer::M1.set(SYSTIMER_CLO.load().wrapping_add(INTERVAL_MS * 1000));
+    boot_clock = clock();
+    last_clock = SYSTIMER_CLO.load();
+    Timer::M1.set(last_clock.wrapping_add(INTERVAL_MS * 1000));
     Timer::M1.enable();
 }
 
+// 前回からの経過時間をミリ秒単位で返し、計上済みとする
+#[inline]
+unsafe fn elapsed_ms() -> u32 {
+    let elapsed_ms = SYSTIMER_CLO.load().wrapping_sub(last_clock) / 1000;
+    last_clock = last_clock.wrapping_add(elapsed_ms * 1000);
+    elapsed_ms
+}
+
+#[inline]
+unsafe fn account(elapsed_ms: u32) {
+    if elapsed_ms > 0 {
+        timer::manager().tick(elapsed_ms as usize);
+    }
+}
+
+/// 周期的な割り込みを止め、`delay`ミリ秒後に一度だけ割り込みを発生させる。
+/// `delay`が`None`の場合は可能な限り長く待機する。
+///
+/// 割り込みが禁止された状態で呼ぶ必要がある。
+pub fn stop_tick(delay: Option<usize>) {
+    let ms = cmp::min(delay.unwrap_or(MAX_ONESHOT_MS), MAX_ONESHOT_MS) as u32;
+    if ms <= INTERVAL_MS {
+        // 周期的な割り込みの方が早い
+        return;
+    }
+
+    unsafe {
+        oneshot = true;
+        Timer::M1.set(last_clock.wrapping_add(ms * 1000));
+    }
+}
+
+/// `stop_tick`で止めた周期的な割り込みを再開し、経過時間を計上する。
+///
+/// 割り込みが禁止された状態で呼ぶ必要がある。
+pub fn restart_tick() {
+    unsafe {
+        if !oneshot {
+            return;
+        }
+        oneshot = false;
+
+        let elapsed_ms = elapsed_ms();
+        Timer::M1.set(last_clock.wrapping_add(INTERVAL_MS * 1000));
+        Timer::M1.enable();
+
+        account(elapsed_ms);
+    }
+}
+
 unsafe fn irq_handler(_irq: pic::IRQ) {
-    let tick = Timer::M1.tick();
-    let elapsed_ms = ((SYSTIMER_CLO.load() - tick) / 1000 + 1) * INTERVAL_MS;
-    Timer::M1.set(tick.wrapping_add(elapsed_ms * 1000));
+    oneshot = false;
+
+    // タイマーのコールバックでタスクが切り替わる可能性があるため先に次の割り込みを設定する
+    let elapsed_ms = elapsed_ms();
+    Timer::M1.set(last_clock.wrapping_add(INTERVAL_MS * 1000));
     Timer::M1.enable();
 
-    timer::manager().tick(elapsed_ms as usize);
+    account(elapsed_ms);
 }
 
 pub fn clock() -> u64 {
@@ -104,3 +167,10 @@ pub fn clock() -> u64 {
     }
 }
 
+
+/// 起動時からの経過時間をナノ秒単位で返す。
+/// 1MHzのシステムタイマーを使うため、分解能は1マイクロ秒。
+#[inline]
+pub fn nanos() -> u64 {
+    unsafe { (clock() - boot_clock) * 1000 }
+}
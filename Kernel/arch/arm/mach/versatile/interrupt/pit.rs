@@ -1,10 +1,11 @@
 #![allow(dead_code)]
 
-use rt::Register;
+use rt::{Register, IntBlocker};
 use arch;
 use arch::page::PageTable;
 use super::pic::IRQ;
 use timer;
+use core::cmp;
 use core::sync::atomic::{self, Ordering};
 
 const TIMER_LOAD:    arch::AddrType = 0x00;
@@ -36,19 +37,84 @@ const TIMER_32BIT:      u32 = 1 << 1;
 const TIMER_ONESHOT:    u32 = 1 << 0;
 
 const FREQ: u32 = 100;
+// ワンショットで待機できる最大の時間
+const MAX_ONESHOT_MS: usize = 1000 * 1000;
+
+// 最後に経過時間を計上した時のTimer1の値
+static mut last_clock: u32 = 0;
+static mut oneshot: bool = false;
+// Timer1を64ビットに拡張するための上位の値と、前回読み出した値
+static mut clock_high: u64 = 0;
+static mut clock_last: u32 = 0;
+// 起動時の拡張したカウンタの値
+static mut boot_clock: u64 = 0;
 
 #[inline]
-pub unsafe fn init() {
+unsafe fn start_periodic() {
+    TIMER0_CONTROL.store(0);
     TIMER0_LOAD.store(1000 * 1000 / FREQ);
     TIMER0_CONTROL.store(TIMER_ENABLE | TIMER_PERIODIC | TIMER_32BIT | TIMER_INT_ENABLE);
-    TIMER0_INTCLR.store(0);
+}
 
+#[inline]
+pub unsafe fn init() {
     TIMER1_CONTROL.store(TIMER_ENABLE | TIMER_32BIT);
+    last_clock = clock() as u32;
+    boot_clock = clock64();
+
+    start_periodic();
+    TIMER0_INTCLR.store(0);
 
     IRQ::Timer01.set_handler(irq_handler);
     IRQ::Timer01.enable();
 }
 
+// 前回からの経過時間を計上する
+unsafe fn account() {
+    // 一周を見逃さないように定期的に読み出す
+    clock64();
+
+    let elapsed_ms = (clock() as u32).wrapping_sub(last_clock) / 1000;
+    last_clock = last_clock.wrapping_add(elapsed_ms * 1000);
+    if elapsed_ms > 0 {
+        timer::manager().tick(elapsed_ms as usize);
+    }
+}
+
+/// 周期的な割り込みを止め、`delay`ミリ秒後に一度だけ割り込みを発生させる。
+/// `delay`が`None`の場合は可能な限り長く待機する。
+///
+/// 割り込みが禁止された状態で呼ぶ必要がある。
+pub fn stop_tick(delay: Option<usize>) {
+    let ms = cmp::min(delay.unwrap_or(MAX_ONESHOT_MS), MAX_ONESHOT_MS) as u32;
+    if ms <= 1000 / FREQ {
+        // 周期的な割り込みの方が早い
+        return;
+    }
+
+    unsafe {
+        oneshot = true;
+        TIMER0_CONTROL.store(0);
+        TIMER0_LOAD.store(ms * 1000);
+        TIMER0_CONTROL.store(TIMER_ENABLE | TIMER_ONESHOT | TIMER_32BIT | TIMER_INT_ENABLE);
+    }
+}
+
+/// `stop_tick`で止めた周期的な割り込みを再開し、経過時間を計上する。
+///
+/// 割り込みが禁止された状態で呼ぶ必要がある。
+pub fn restart_tick() {
+    unsafe {
+        if !oneshot {
+            return;
+        }
+        oneshot = false;
+
+        start_periodic();
+        account();
+    }
+}
+
 #[inline(always)]
 pub fn map_pages(table: &mut PageTable) {
     table.map_direct(PageTable::FLAGS_KERNEL,
@@ -62,8 +128,14 @@ unsafe fn irq_handler(irq: IRQ) {
         IRQ::Timer01 if TIMER0_MIS.load() == 1 => {
             TIMER0_INTCLR.store(0);
 
+            // タイマーのコールバックでタスクが切り替わる可能性があるため先に周期モードに戻す
+            if oneshot {
+                oneshot = false;
+                start_periodic();
+            }
+
             atomic::fence(Ordering::Acquire);
-            timer::manager().tick(1000 / FREQ as usize);
+            account();
         },
         _ => unreachable!()
     }
@@ -73,3 +145,24 @@ pub fn clock() -> u64 {
     !TIMER1_VALUE.load() as u64
 }
 
+
+// Timer1の値を64ビットに拡張して返す
+// 一周する(約71分)までに1回以上呼ばれる必要がある
+fn clock64() -> u64 {
+    let _blocker = IntBlocker::new();
+    unsafe {
+        let raw = clock() as u32;
+        if raw < clock_last {
+            clock_high += 1 << 32;
+        }
+        clock_last = raw;
+        clock_high | raw as u64
+    }
+}
+
+/// 起動時からの経過時間をナノ秒単位で返す。
+/// 1MHzのTimer1を使うため、分解能は1マイクロ秒。
+#[inline]
+pub fn nanos() -> u64 {
+    (clock64() - unsafe { boot_clock }) * 1000
+}
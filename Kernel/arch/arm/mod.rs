@@ -26,6 +26,8 @@ pub mod interrupt;
 
 pub mod task;
 
+pub mod smp;
+
 pub mod drivers;
 
 pub const PAGE_SIZE: usize = 0x1000;
@@ -116,6 +118,16 @@ pub fn print_backtrace() {
     }
 }
 
+/// 呼び出し元からの戻りアドレスを`buf`に格納し、格納した数を返す。
+pub fn capture_backtrace(buf: &mut [usize]) -> usize {
+    let mut len = 0;
+    for (slot, pc) in buf.iter_mut().zip(StackFrame::new()) {
+        *slot = pc as usize;
+        len += 1;
+    }
+    len
+}
+
 pub fn print_registers() {
     unsafe {
         let mut r: [u32; 15] = [0; 15];
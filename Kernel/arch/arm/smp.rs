@@ -0,0 +1,26 @@
+/// 扱えるプロセッサの最大数
+pub const MAX_CPUS: usize = 1;
+
+/// 実行中のプロセッサの番号を返す。
+#[inline(always)]
+pub fn cpu_id() -> usize {
+    0
+}
+
+/// 動作しているプロセッサの数を返す。
+#[inline(always)]
+pub fn cpu_count() -> usize {
+    1
+}
+
+/// `cpu`番のプロセッサにタスクの切り替えを要求する。
+/// 他のプロセッサは無いので呼ばれることは無い。
+#[inline]
+pub fn reschedule(cpu: usize) {
+    panic!("No such CPU: {}", cpu);
+}
+
+/// 他のプロセッサを起動する。マルチプロセッサには対応していないので何もしない。
+#[inline(always)]
+pub fn init() {
+}
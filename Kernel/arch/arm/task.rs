@@ -67,3 +67,7 @@ pub unsafe fn leap(next_task: &mut TaskEntity) -> ! {
     task_leap(&mut next_task.regs);
 }
 
+#[inline]
+pub unsafe fn enter_user(_entry: usize, _stack: usize) -> ! {
+    panic!("User mode is not supported");
+}
@@ -1,2 +1,2 @@
-ASMS = memory.S interrupt/handlers.S interrupt/jump.S
+ASMS = memory.S interrupt/handlers.S interrupt/jump.S smp.S
 
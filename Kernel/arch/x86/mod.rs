@@ -34,12 +34,24 @@ pub mod serial;
 #[path = "../x86_common/page.rs"]
 pub mod page;
 
+#[path = "../x86_common/bios.rs"]
+mod bios;
+
+#[path = "../x86_common/acpi.rs"]
+pub mod acpi;
+
+#[path = "../x86_common/mptable.rs"]
+pub mod mptable;
+
 #[path = "../x86_common/interrupt/mod.rs"]
 pub mod interrupt;
 
 #[path = "../x86_common/task.rs"]
 pub mod task;
 
+#[path = "../x86_common/smp.rs"]
+pub mod smp;
+
 #[path = "../x86_common/drivers/mod.rs"]
 pub mod drivers;
 
@@ -108,6 +120,27 @@ pub fn print_backtrace() {
     }
 }
 
+/// 呼び出し元からの戻りアドレスを`buf`に格納し、格納した数を返す。
+pub fn capture_backtrace(buf: &mut [usize]) -> usize {
+    let mut bp: u32;
+    unsafe {
+        asm!("mov %ebp, $0" : "=r"(bp) ::: "volatile");
+    }
+
+    let mut len = 0;
+    while len < buf.len() {
+        match backtrace(bp) {
+            Some((newbp, ip)) => {
+                buf[len] = ip as usize;
+                len += 1;
+                bp = newbp;
+            },
+            None => break
+        }
+    }
+    len
+}
+
 pub fn backtrace(bp: u32) -> Option<(u32, u32)> {
     if bp == 0 || bp % 4 != 0 {
         None
@@ -0,0 +1,321 @@
+#![allow(dead_code)]
+
+use arch::bios;
+use arch::page::TempMapping;
+use memory::kernel::PhysAddr;
+use core::mem;
+use core::u32;
+
+// RSDPを探すBIOSの領域の先頭
+const BIOS_AREA_START: u64 = 0x000E0000;
+
+const RSDP_SIGNATURE: &'static [u8; 8] = b"RSD PTR ";
+const MADT_SIGNATURE: &'static [u8; 4] = b"APIC";
+
+// MADTのエントリの種類
+const MADT_LOCAL_APIC:          u8 = 0;
+const MADT_IO_APIC:             u8 = 1;
+const MADT_INTERRUPT_OVERRIDE:  u8 = 2;
+const MADT_LOCAL_APIC_OVERRIDE: u8 = 5;
+
+// MADTのフラグ。8259が実装されていることを示す
+const MADT_FLAG_PCAT_COMPAT: u32 = 1 << 0;
+// Processor Local APICのフラグ。プロセッサが使えることを示す
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+
+/// 扱えるI/O APICの最大数
+pub const MAX_IO_APICS: usize = 8;
+/// 扱えるプロセッサの最大数
+pub const MAX_CPUS: usize = 32;
+/// ISA割り込みの数
+pub const ISA_IRQS: usize = 16;
+
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum:  u8,
+    oem_id:    [u8; 6],
+    revision:  u8,
+    rsdt_addr: u32
+}
+
+#[repr(C, packed)]
+struct SdtHeader {
+    signature:        [u8; 4],
+    length:           u32,
+    revision:         u8,
+    checksum:         u8,
+    oem_id:           [u8; 6],
+    oem_table_id:     [u8; 8],
+    oem_revision:     u32,
+    creator_id:       u32,
+    creator_revision: u32
+}
+
+#[repr(C, packed)]
+struct MadtHeader {
+    header:     SdtHeader,
+    local_apic: u32,
+    flags:      u32
+}
+
+#[repr(C, packed)]
+struct MadtEntry {
+    kind:   u8,
+    length: u8
+}
+
+#[repr(C, packed)]
+struct MadtLocalApic {
+    entry:     MadtEntry,
+    processor: u8,
+    apic_id:   u8,
+    flags:     u32
+}
+
+#[repr(C, packed)]
+struct MadtIoApic {
+    entry:    MadtEntry,
+    id:       u8,
+    reserved: u8,
+    addr:     u32,
+    gsi_base: u32
+}
+
+#[repr(C, packed)]
+struct MadtInterruptOverride {
+    entry:  MadtEntry,
+    bus:    u8,
+    source: u8,
+    gsi:    u32,
+    flags:  u16
+}
+
+#[repr(C, packed)]
+struct MadtLocalApicOverride {
+    entry:    MadtEntry,
+    reserved: u16,
+    addr:     u64
+}
+
+/// I/O APICの情報
+#[derive(Clone, Copy)]
+pub struct IoApic {
+    pub id:       u8,
+    pub addr:     PhysAddr,
+    pub gsi_base: u32
+}
+
+/// ISA割り込みの接続先
+#[derive(Clone, Copy)]
+pub struct IsaIrq {
+    /// 接続されているグローバルシステム割り込みの番号
+    pub gsi:        u32,
+    /// アクティブローならば`true`
+    pub active_low: bool,
+    /// レベルトリガならば`true`
+    pub level:      bool
+}
+
+/// MADTから読み出した割り込みコントローラとプロセッサの情報
+pub struct Madt {
+    local_apic: PhysAddr,
+    pic_compat: bool,
+    io_apics:   [IoApic; MAX_IO_APICS],
+    io_apic_count: usize,
+    isa_irqs:   [IsaIrq; ISA_IRQS],
+    cpus:       [u8; MAX_CPUS],
+    cpu_count:  usize
+}
+
+impl Madt {
+    /// Local APICのレジスタの物理アドレスを返す。
+    #[inline]
+    pub fn local_apic(&self) -> PhysAddr {
+        self.local_apic
+    }
+
+    /// 8259も実装されているかどうかを返す。
+    #[inline]
+    pub fn pic_compat(&self) -> bool {
+        self.pic_compat
+    }
+
+    /// I/O APICの一覧を返す。
+    #[inline]
+    pub fn io_apics(&self) -> &[IoApic] {
+        &self.io_apics[.. self.io_apic_count]
+    }
+
+    /// ISA割り込み`irq`の接続先を返す。
+    #[inline]
+    pub fn isa_irq(&self, irq: u8) -> IsaIrq {
+        self.isa_irqs[irq as usize]
+    }
+
+    /// 使えるプロセッサのLocal APIC IDの一覧を返す。
+    #[inline]
+    pub fn cpus(&self) -> &[u8] {
+        &self.cpus[.. self.cpu_count]
+    }
+}
+
+static mut madt: Option<Madt> = None;
+
+unsafe fn find_rsdp_in(start: u64, size: usize) -> Option<&'static Rsdp> {
+    bios::scan(start, size, RSDP_SIGNATURE, 16, |_: &Rsdp| mem::size_of::<Rsdp>())
+}
+
+// EBDAの先頭1KBとBIOSの領域からRSDPを探す
+unsafe fn find_rsdp() -> Option<&'static Rsdp> {
+    if let Some(ebda) = bios::ebda() {
+        if let Some(rsdp) = find_rsdp_in(ebda, bios::EBDA_SEARCH_SIZE) {
+            return Some(rsdp);
+        }
+    }
+    find_rsdp_in(BIOS_AREA_START, (bios::BIOS_AREA_END - BIOS_AREA_START) as usize)
+}
+
+// 物理アドレス`addr`にあるテーブル全体を一時的に対応付け、チェックサムが正しければ返す
+// テーブルはカーネルの領域の外にあることが多いので、`as_virt_addr`は使えない
+unsafe fn map_table(addr: u32) -> Option<TempMapping> {
+    let addr = PhysAddr::from_raw(addr as u64);
+    let length = match TempMapping::new(addr, mem::size_of::<SdtHeader>()) {
+        Some(header) => (*header.as_ptr::<SdtHeader>()).length as usize,
+        None => return None
+    };
+    if length < mem::size_of::<SdtHeader>() {
+        return None;
+    }
+
+    let table = match TempMapping::new(addr, length) {
+        Some(table) => table,
+        None => return None
+    };
+    if bios::checksum(table.as_ptr(), length) {
+        Some(table)
+    } else {
+        None
+    }
+}
+
+// RSDTのエントリからシグネチャが`signature`のテーブルを探す
+// 他のテーブルはヘッダーだけを読み、全体は対応付けない
+unsafe fn find_table(rsdt: &SdtHeader, signature: &[u8; 4]) -> Option<TempMapping> {
+    let count = (rsdt.length as usize - mem::size_of::<SdtHeader>()) / u32::BYTES;
+    let entries = (rsdt as *const SdtHeader).offset(1) as *const u32;
+    for i in 0 .. count {
+        let addr = *entries.offset(i as isize);
+        let matched = TempMapping::new(PhysAddr::from_raw(addr as u64), mem::size_of::<SdtHeader>())
+            .map_or(false, |header| &(*header.as_ptr::<SdtHeader>()).signature == signature);
+        if matched {
+            return map_table(addr);
+        }
+    }
+    None
+}
+
+unsafe fn parse_madt(header: &SdtHeader) -> Madt {
+    let madt_header = &*(header as *const SdtHeader as *const MadtHeader);
+    let mut info = Madt {
+        local_apic: PhysAddr::from_raw(madt_header.local_apic as u64),
+        pic_compat: madt_header.flags & MADT_FLAG_PCAT_COMPAT != 0,
+        io_apics: [IoApic { id: 0, addr: PhysAddr::null(), gsi_base: 0 }; MAX_IO_APICS],
+        io_apic_count: 0,
+        isa_irqs: [IsaIrq { gsi: 0, active_low: false, level: false }; ISA_IRQS],
+        cpus: [0; MAX_CPUS],
+        cpu_count: 0
+    };
+    // 上書きされない限りISA割り込みは同じ番号のグローバルシステム割り込みに繋がっている
+    for (i, irq) in info.isa_irqs.iter_mut().enumerate() {
+        irq.gsi = i as u32;
+    }
+
+    let start = (madt_header as *const MadtHeader).offset(1) as usize;
+    let end = header as *const SdtHeader as usize + header.length as usize;
+    let mut ptr = start;
+    while ptr + mem::size_of::<MadtEntry>() <= end {
+        let entry = &*(ptr as *const MadtEntry);
+        if entry.length == 0 {
+            break;
+        }
+
+        match entry.kind {
+            MADT_LOCAL_APIC => {
+                let local_apic = &*(ptr as *const MadtLocalApic);
+                if local_apic.flags & LOCAL_APIC_ENABLED != 0 && info.cpu_count < MAX_CPUS {
+                    info.cpus[info.cpu_count] = local_apic.apic_id;
+                    info.cpu_count += 1;
+                }
+            },
+            MADT_IO_APIC => {
+                let io_apic = &*(ptr as *const MadtIoApic);
+                if info.io_apic_count < MAX_IO_APICS {
+                    info.io_apics[info.io_apic_count] = IoApic {
+                        id: io_apic.id,
+                        addr: PhysAddr::from_raw(io_apic.addr as u64),
+                        gsi_base: io_apic.gsi_base
+                    };
+                    info.io_apic_count += 1;
+                }
+            },
+            MADT_INTERRUPT_OVERRIDE => {
+                let over = &*(ptr as *const MadtInterruptOverride);
+                if over.bus == 0 && (over.source as usize) < ISA_IRQS {
+                    // ビット0-1が極性、ビット2-3がトリガモード。0はISAバスの既定(アクティブハイ、エッジ)
+                    info.isa_irqs[over.source as usize] = IsaIrq {
+                        gsi: over.gsi,
+                        active_low: over.flags & 0x03 == 0x03,
+                        level: over.flags >> 2 & 0x03 == 0x03
+                    };
+                }
+            },
+            MADT_LOCAL_APIC_OVERRIDE => {
+                let over = &*(ptr as *const MadtLocalApicOverride);
+                info.local_apic = PhysAddr::from_raw(over.addr);
+            },
+            _ => ()
+        }
+
+        ptr += entry.length as usize;
+    }
+
+    info
+}
+
+/// RSDPからRSDTを辿ってMADTを読み出す。MADTが見つかった場合は`true`を返す。
+/// 読み出した内容は複製して保持し、テーブルの対応付けは外す。
+///
+/// 仮想メモリが有効になってから、他のプロセッサを起動する前に呼ぶ必要がある。
+pub fn init() -> bool {
+    unsafe {
+        let rsdt = match find_rsdp().and_then(|rsdp| map_table(rsdp.rsdt_addr)) {
+            Some(rsdt) => rsdt,
+            None => {
+                log!("ACPI: RSDT not found");
+                return false;
+            }
+        };
+        let table = find_table(&*rsdt.as_ptr::<SdtHeader>(), MADT_SIGNATURE);
+        drop(rsdt);
+
+        match table {
+            Some(table) => {
+                let info = parse_madt(&*table.as_ptr::<SdtHeader>());
+                log!("ACPI: {} CPU(s), {} I/O APIC(s)", info.cpus().len(), info.io_apics().len());
+                madt = Some(info);
+                true
+            },
+            None => {
+                log!("ACPI: MADT not found");
+                false
+            }
+        }
+    }
+}
+
+/// MADTの情報を返す。`init`でMADTが見つからなかった場合は`None`を返す。
+#[inline]
+pub fn madt() -> Option<&'static Madt> {
+    unsafe { madt.as_ref() }
+}
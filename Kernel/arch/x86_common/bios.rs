@@ -0,0 +1,46 @@
+#![allow(dead_code)]
+
+use memory::kernel::PhysAddr;
+use core::slice;
+
+// EBDAのセグメントが書かれているBIOS Data Areaのアドレス
+const EBDA_SEGMENT_PTR: u64 = 0x040E;
+
+/// EBDAのうち、テーブルを探す先頭の範囲
+pub const EBDA_SEARCH_SIZE: usize = 0x0400;
+
+/// BIOSの領域の終わり
+pub const BIOS_AREA_END: u64 = 0x00100000;
+
+/// `ptr`から`len`バイトの和が0ならtrueを返す
+pub fn checksum(ptr: *const u8, len: usize) -> bool {
+    let bytes = unsafe { slice::from_raw_parts(ptr, len) };
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+/// EBDAの物理アドレスを返す。BIOS Data Areaに書かれていなければNone
+pub unsafe fn ebda() -> Option<u64> {
+    let segment = *PhysAddr::from_raw(EBDA_SEGMENT_PTR).as_virt_addr().as_ptr::<u16>();
+    match segment {
+        0 => None,
+        segment => Some((segment as u64) << 4)
+    }
+}
+
+/// 物理アドレス`start`から`size`バイトを`step`バイトごとに調べ、`signature`で始まり
+/// チェックサムが正しい構造体を返す。チェックサムをとる長さは`len`で求める
+pub unsafe fn scan<T, F>(start: u64, size: usize, signature: &[u8], step: usize, len: F) -> Option<&'static T>
+    where F: Fn(&T) -> usize {
+    let base = PhysAddr::from_raw(start).as_virt_addr();
+    for offset in (0 .. size).step_by(step) {
+        let ptr = (base + offset).as_ptr::<u8>();
+        if slice::from_raw_parts(ptr, signature.len()) != signature {
+            continue;
+        }
+        let value = &*(ptr as *const T);
+        if checksum(ptr, len(value)) {
+            return Some(value);
+        }
+    }
+    None
+}
@@ -51,7 +51,7 @@ mod keyboard {
                 code if code & 0x80 == 0                             => Keyboard::Down(state.clone()),
                 _                                                    => Keyboard::Up(state.clone()),
             };
-            event::queue().push(Event::Device(Device::Keyboard(key)));
+            event::post_from_isr(Event::Device(Device::Keyboard(key)));
         }
     }
 }
@@ -126,7 +126,7 @@ mod mouse {
                 Stage::Third(flags, x)           => {
                     let y = data as i8;
                     let mouse = Mouse::with_bits(flags, x as i8, -y);
-                    event::queue().push(Event::Device(Device::Mouse(mouse)));
+                    event::post_from_isr(Event::Device(Device::Mouse(mouse)));
                     Stage::First
                 }
             };
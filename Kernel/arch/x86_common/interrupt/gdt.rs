@@ -1,4 +1,6 @@
 use arch::interrupt;
+use arch::smp::{self, MAX_CPUS};
+use core::mem;
 use core::u64;
 
 extern "C" {
@@ -6,6 +8,7 @@ extern "C" {
 }
 
 #[repr(C, packed)]
+#[derive(Clone, Copy)]
 struct Gdtr {
     len: u16,
     ptr: u32
@@ -34,21 +37,75 @@ pub unsafe fn pre_init() {
     asm!("lgdtl ($0)" :: "r"(&gdtr) :: "volatile");
 }
 
+// 32ビットTSS。特権レベルが変わる割り込みで使うスタックだけを設定する
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct TaskStateSegment {
+    link:       u32,
+    esp0:       u32,
+    ss0:        u32,
+    esp1:       u32,
+    ss1:        u32,
+    esp2:       u32,
+    ss2:        u32,
+    cr3:        u32,
+    eip:        u32,
+    eflags:     u32,
+    regs:       [u32; 8],
+    segments:   [u32; 6],
+    ldt:        u32,
+    trap:       u16,
+    iomap_base: u16
+}
+
+const TSS_INIT: TaskStateSegment = TaskStateSegment {
+    link: 0, esp0: 0, ss0: 0, esp1: 0, ss1: 0, esp2: 0, ss2: 0, cr3: 0, eip: 0, eflags: 0,
+    regs: [0; 8], segments: [0; 6], ldt: 0, trap: 0, iomap_base: 0
+};
+
+// TSSは使用中の印がGDTに付くので、プロセッサごとにGDTとTSSを持つ
+static mut init_gdt: [[u64; interrupt::GDT_ENTRIES]; MAX_CPUS] = [[0; interrupt::GDT_ENTRIES]; MAX_CPUS];
+static mut init_gdtr: [Gdtr; MAX_CPUS] = [Gdtr {
+    len: (interrupt::GDT_ENTRIES * u64::BYTES - 1) as u16,
+    ptr: 0
+}; MAX_CPUS];
+static mut tss: [TaskStateSegment; MAX_CPUS] = [TSS_INIT; MAX_CPUS];
+
 #[inline]
 pub unsafe fn init() {
-    static mut init_gdt: [u64; interrupt::GDT_ENTRIES] = [0; interrupt::GDT_ENTRIES];
-    init_gdt[interrupt::GDT_ENTRY_KERNEL_CS] = gdt_entry(0, 0xFFFFF, 0xC09A);
-    init_gdt[interrupt::GDT_ENTRY_KERNEL_DS] = gdt_entry(0, 0xFFFFF, 0xC092);
-    init_gdt[interrupt::GDT_ENTRY_DEFAULT_USER_CS] = gdt_entry(0, 0xFFFFF, 0xC0FA);
-    init_gdt[interrupt::GDT_ENTRY_DEFAULT_USER_DS] = gdt_entry(0, 0xFFFFF, 0xC0F2);
+    load(0);
+}
 
-    static mut gdtr: Gdtr = Gdtr {
-        len: (interrupt::GDT_ENTRIES * u64::BYTES - 1) as u16,
-        ptr: 0
-    };
-    gdtr.ptr = init_gdt.as_mut_ptr() as u32;
+/// 他のプロセッサのGDTとTSSを作って読み込む。
+#[inline]
+pub unsafe fn init_secondary() {
+    load(smp::cpu_id());
+}
 
-    asm!("lgdtl ($0)" :: "r"(&gdtr) :: "volatile");
+unsafe fn load(cpu: usize) {
+    let gdt = &mut init_gdt[cpu];
+    gdt[interrupt::GDT_ENTRY_KERNEL_CS] = gdt_entry(0, 0xFFFFF, 0xC09A);
+    gdt[interrupt::GDT_ENTRY_KERNEL_DS] = gdt_entry(0, 0xFFFFF, 0xC092);
+    gdt[interrupt::GDT_ENTRY_DEFAULT_USER_CS] = gdt_entry(0, 0xFFFFF, 0xC0FA);
+    gdt[interrupt::GDT_ENTRY_DEFAULT_USER_DS] = gdt_entry(0, 0xFFFFF, 0xC0F2);
+
+    // I/O許可ビットマップは置かないので、ユーザーモードからのポート入出力は全て例外になる
+    tss[cpu].ss0 = interrupt::KERNEL_DS as u32;
+    tss[cpu].iomap_base = mem::size_of::<TaskStateSegment>() as u16;
+    gdt[interrupt::GDT_ENTRY_TSS] = gdt_entry(&tss[cpu] as *const TaskStateSegment as u32,
+                                              mem::size_of::<TaskStateSegment>() as u32 - 1, 0x0089);
+
+    init_gdtr[cpu].ptr = gdt.as_mut_ptr() as u32;
+    asm!("lgdtl ($0)" :: "r"(&init_gdtr[cpu]) :: "volatile");
     flush_gdt(interrupt::KERNEL_CS as u16, interrupt::KERNEL_DS as u16);
+    asm!("ltr %ax" :: "{ax}"(interrupt::TSS as u16) :: "volatile");
 }
 
+/// ユーザーモードから割り込みや例外で戻ったときに使うスタックを設定する。
+/// タスクを切り替えるたびに、切り替え先のタスクのカーネルスタックの末尾を渡す。
+#[inline]
+pub fn set_kernel_stack(stack_top: usize) {
+    unsafe {
+        tss[smp::cpu_id()].esp0 = stack_top as u32;
+    }
+}
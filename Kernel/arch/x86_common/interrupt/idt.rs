@@ -1,6 +1,8 @@
 #![allow(dead_code)]
 
 use super::pic::IRQ;
+use super::syscall;
+use task;
 use core::mem;
 
 const INT_DIVISION_BY_ZERO:             u8 = 0x00;
@@ -106,6 +108,10 @@ extern "C" {
     fn idt_0c_handler();
     fn idt_0d_handler();
     fn idt_0e_handler();
+    fn idt_30_handler();
+    fn idt_31_handler();
+    fn idt_80_handler();
+    fn idt_ff_handler();
 
     fn irq_handler_0();
     fn irq_handler_1();
@@ -159,16 +165,39 @@ pub unsafe fn init() {
     idt.set_interrupt(0x2E, irq_handler_14);
     idt.set_interrupt(0x2F, irq_handler_15);
 
+    idt.set_interrupt(super::lapic::TIMER_VECTOR as usize, idt_30_handler);
+    idt.set_interrupt(super::lapic::RESCHEDULE_VECTOR as usize, idt_31_handler);
+    idt.set_interrupt(super::lapic::SPURIOUS_VECTOR as usize, idt_ff_handler);
+
+    idt.set_user_interrupt(syscall::SYSCALL_VECTOR as usize, idt_80_handler);
+
     idt.load();
 }
 
+/// `init`で作ったIDTを他のプロセッサに読み込む。
+#[inline]
+pub unsafe fn init_secondary() {
+    idt.load();
+}
+
+// 例外がユーザーモードで起きていれば、カーネルを止めずにそのタスクを終了する。
+// `frame`は例外で積まれたeip, cs, eflagsを指す
+unsafe fn terminate_if_user(message: &str, frame: *const u32) {
+    if *frame.offset(1) & 3 == 3 {
+        log!("{} in user mode at {:p}, terminating task {}", message, *frame as *const u8, task::this().id());
+        task::exit();
+    }
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn idt_empty_handler(esp: *const u32) {
+    terminate_if_user("Unhandled interrupt", esp);
     panic!("Unhandled interrupt at {:p}", *esp as *const u8);
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn invalid_opcode_handler(esp: *const u32) {
+    terminate_if_user("Invalid opcode", esp);
     panic!("Invalid opcode (may out of memory occurred) at {:p}", *esp as *const u8);
 }
 
@@ -187,17 +216,20 @@ fn selector_error_panic(message: &str, code: u32) -> ! {
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn stack_segment_fault_handler(code: u32) {
-    selector_error_panic("Stack-segment fault", code);
+pub unsafe extern "C" fn stack_segment_fault_handler(esp: *const u32) {
+    terminate_if_user("Stack-segment fault", esp.offset(1));
+    selector_error_panic("Stack-segment fault", *esp);
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn general_protection_fault_handler(code: u32) {
-    selector_error_panic("General protection fault", code);
+pub unsafe extern "C" fn general_protection_fault_handler(esp: *const u32) {
+    terminate_if_user("General protection fault", esp.offset(1));
+    selector_error_panic("General protection fault", *esp);
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn page_fault_handler(esp: *const u32, address: u32) {
+    terminate_if_user("Page fault", esp.offset(1));
     panic!("Page fault {} to {:p} at {:p}", *esp.offset(0), address as *const u8, *esp.offset(1) as *const u8);
 }
 
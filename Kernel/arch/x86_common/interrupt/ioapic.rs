@@ -0,0 +1,144 @@
+#![allow(dead_code)]
+
+use arch;
+use arch::acpi::{self, IoApic, ISA_IRQS};
+use arch::page::{self, PageTable};
+use rt::Register;
+use sync::SpinLock;
+use super::pic::IRQ;
+use super::lapic;
+
+const REG_SELECT: arch::AddrType = 0x00;
+const REG_WINDOW: arch::AddrType = 0x10;
+
+const IOAPIC_ID:          u32 = 0x00;
+const IOAPIC_VERSION:     u32 = 0x01;
+const IOAPIC_REDIRECTION: u32 = 0x10;
+
+// Redirection Entry(下位32ビット)
+const REDIRECTION_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECTION_LEVEL:      u32 = 1 << 15;
+const REDIRECTION_MASKED:     u32 = 1 << 16;
+
+// ISA割り込みに割り当てる割り込みベクタの先頭。8259と同じにしてIRQのハンドラを共有する
+const ISA_VECTOR_BASE: u32 = 0x20;
+
+#[derive(Clone, Copy)]
+struct Route {
+    base:  Register<u32>,
+    pin:   u32,
+    entry: u32
+}
+
+// ISA割り込みの接続先のI/O APICの入力
+static mut routes: [Option<Route>; ISA_IRQS] = [None; ISA_IRQS];
+static mut enabled: bool = false;
+
+// 選択と読み書きのレジスタを組で使うので、他のプロセッサと同時に操作しないようにする
+static LOCK: SpinLock<()> = SpinLock::new(());
+
+#[inline]
+unsafe fn read(base: Register<u32>, reg: u32) -> u32 {
+    base.offset(REG_SELECT).store(reg);
+    base.offset(REG_WINDOW).load()
+}
+
+#[inline]
+unsafe fn write(base: Register<u32>, reg: u32, val: u32) {
+    base.offset(REG_SELECT).store(reg);
+    base.offset(REG_WINDOW).store(val);
+}
+
+#[inline]
+fn pins(io_apic: &IoApic) -> u32 {
+    unsafe {
+        (read(Register::new(io_apic.addr.value()), IOAPIC_VERSION) >> 16 & 0xFF) + 1
+    }
+}
+
+/// ACPIがI/O APICの存在を報告しているかどうかを返す。
+pub fn is_available() -> bool {
+    acpi::madt().map_or(false, |madt| !madt.io_apics().is_empty())
+}
+
+/// I/O APICで割り込みを扱っているかどうかを返す。
+#[inline(always)]
+pub fn is_enabled() -> bool {
+    unsafe { enabled }
+}
+
+/// I/O APICのレジスタを対応付け、ISA割り込みを現在のプロセッサに配送するように設定する。
+/// 各割り込みは`IRQ::enable`を呼ぶまで止めておく。
+pub unsafe fn init() {
+    let madt = acpi::madt().expect("MADT is not available");
+
+    for io_apic in madt.io_apics() {
+        let base = Register::new(io_apic.addr.value());
+        page::table().map_direct(PageTable::FLAGS_DEVICE, base.addr(), arch::PAGE_SIZE);
+
+        for pin in 0 .. pins(io_apic) {
+            write(base, IOAPIC_REDIRECTION + pin * 2, REDIRECTION_MASKED);
+        }
+        log!("I/O APIC: id {} at {:?} GSI {}-{}",
+             io_apic.id, io_apic.addr, io_apic.gsi_base, io_apic.gsi_base + pins(io_apic) - 1);
+    }
+
+    let dest = lapic::id() as u32;
+    for irq in 0 .. ISA_IRQS as u8 {
+        let isa = madt.isa_irq(irq);
+
+        // 上書きによって他のISA割り込みが同じ入力に繋がっている場合はそちらを優先する
+        let shadowed = (0 .. ISA_IRQS as u8).any(|other| {
+            let other_isa = madt.isa_irq(other);
+            other != irq && other_isa.gsi != other as u32 && other_isa.gsi == isa.gsi
+        });
+        if shadowed {
+            continue;
+        }
+
+        let io_apic = madt.io_apics().iter()
+            .find(|io_apic| io_apic.gsi_base <= isa.gsi && isa.gsi < io_apic.gsi_base + pins(io_apic));
+        if let Some(io_apic) = io_apic {
+            let mut entry = ISA_VECTOR_BASE + irq as u32;
+            if isa.active_low {
+                entry |= REDIRECTION_ACTIVE_LOW;
+            }
+            if isa.level {
+                entry |= REDIRECTION_LEVEL;
+            }
+
+            let route = Route {
+                base: Register::new(io_apic.addr.value()),
+                pin: isa.gsi - io_apic.gsi_base,
+                entry: entry
+            };
+            write(route.base, IOAPIC_REDIRECTION + route.pin * 2 + 1, dest << 24);
+            write(route.base, IOAPIC_REDIRECTION + route.pin * 2, entry | REDIRECTION_MASKED);
+            routes[irq as usize] = Some(route);
+        }
+    }
+
+    enabled = true;
+}
+
+fn set_masked(irq: IRQ, masked: bool) {
+    unsafe {
+        if let Some(route) = routes[irq as usize] {
+            let _guard = LOCK.lock();
+            write(route.base, IOAPIC_REDIRECTION + route.pin * 2,
+                  route.entry | if masked { REDIRECTION_MASKED } else { 0 });
+        }
+    }
+}
+
+/// ISA割り込み`irq`を許可する。
+#[inline]
+pub fn unmask(irq: IRQ) {
+    set_masked(irq, false);
+}
+
+/// ISA割り込み`irq`を止める。
+#[inline]
+pub fn mask(irq: IRQ) {
+    set_masked(irq, true);
+}
@@ -0,0 +1,250 @@
+#![allow(dead_code)]
+
+use arch;
+use arch::x86_io::{cpuid, rdmsr, wrmsr};
+use arch::acpi;
+use arch::page::{self, PageTable};
+use rt::{Register, IntBlocker};
+use timer;
+use super::pit;
+use core::{cmp, u32};
+
+const REG_ID:            arch::AddrType = 0x020;
+const REG_VERSION:       arch::AddrType = 0x030;
+const REG_TPR:           arch::AddrType = 0x080;
+const REG_EOI:           arch::AddrType = 0x0B0;
+const REG_SVR:           arch::AddrType = 0x0F0;
+const REG_ICR_LOW:       arch::AddrType = 0x300;
+const REG_ICR_HIGH:      arch::AddrType = 0x310;
+const REG_LVT_TIMER:     arch::AddrType = 0x320;
+const REG_LVT_LINT0:     arch::AddrType = 0x350;
+const REG_LVT_LINT1:     arch::AddrType = 0x360;
+const REG_LVT_ERROR:     arch::AddrType = 0x370;
+const REG_TIMER_INITIAL: arch::AddrType = 0x380;
+const REG_TIMER_CURRENT: arch::AddrType = 0x390;
+const REG_TIMER_DIVIDE:  arch::AddrType = 0x3E0;
+
+const MSR_APIC_BASE:    u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+// CPUID(EAX=1)のEDXでLocal APICが使えることを示すビット
+const CPUID_EDX_APIC: u32 = 1 << 9;
+
+// Spurious Interrupt Vector Register
+const SVR_ENABLE: u32 = 1 << 8;
+
+// Local Vector Table
+const LVT_DELIVERY_NMI:   u32 = 0x4 << 8;
+const LVT_MASKED:         u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+
+const TIMER_DIVIDE_BY_16: u32 = 0x03;
+
+// Interrupt Command Register
+const ICR_DELIVERY_INIT:    u32 = 0x5 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0x6 << 8;
+const ICR_PENDING:          u32 = 1 << 12;
+const ICR_LEVEL_ASSERT:     u32 = 1 << 14;
+
+/// Local APICタイマーの割り込みベクタ
+pub const TIMER_VECTOR: u8 = 0x30;
+/// 他のプロセッサにタスクの切り替えを要求する割り込みのベクタ
+pub const RESCHEDULE_VECTOR: u8 = 0x31;
+/// スプリアス割り込みのベクタ
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+const FREQ: u32 = 100;
+
+static mut base: Register<u32> = Register::new(0xFEE00000);
+static mut enabled: bool = false;
+// タイマーの1ミリ秒あたりのカウント数。タイマーを使っていない場合は0
+static mut ticks_per_ms: u32 = 0;
+// ワンショットで設定したカウント値。周期モードならば0
+static mut oneshot_count: u32 = 0;
+// まだ計上していない経過時間(タイマーのカウント数)
+static mut pending_count: u64 = 0;
+
+#[inline(always)]
+fn reg(offset: arch::AddrType) -> Register<u32> {
+    unsafe { base.offset(offset) }
+}
+
+/// CPUIDとACPIの両方がLocal APICの存在を報告しているかどうかを返す。
+pub fn is_available() -> bool {
+    let (_, _, _, edx) = unsafe { cpuid(1) };
+    edx & CPUID_EDX_APIC != 0 && acpi::madt().is_some()
+}
+
+/// Local APICで割り込みを扱っているかどうかを返す。
+#[inline(always)]
+pub fn is_enabled() -> bool {
+    unsafe { enabled }
+}
+
+/// Local APICのタイマーをティックに使っているかどうかを返す。
+#[inline(always)]
+pub fn is_timer_enabled() -> bool {
+    unsafe { ticks_per_ms != 0 }
+}
+
+/// Local APICのレジスタを対応付けて有効にする。`is_available`が`true`の場合にのみ呼べる。
+pub unsafe fn init() {
+    let madt = acpi::madt().expect("MADT is not available");
+    base = Register::new(madt.local_apic().value());
+    page::table().map_direct(PageTable::FLAGS_DEVICE, base.addr(), arch::PAGE_SIZE);
+
+    init_cpu();
+
+    enabled = true;
+    log!("Local APIC: id {} at {:?}", id(), base.addr());
+}
+
+/// 実行中のプロセッサのLocal APICを有効にする。
+/// 他のプロセッサでは`init`の後に、そのプロセッサの上で呼ぶ。
+pub unsafe fn init_cpu() {
+    wrmsr(MSR_APIC_BASE, rdmsr(MSR_APIC_BASE) | APIC_BASE_ENABLE);
+
+    // 外部割り込みはI/O APICから受け取るのでLINT0は使わない
+    reg(REG_TPR).store(0);
+    reg(REG_LVT_TIMER).store(LVT_MASKED);
+    reg(REG_LVT_LINT0).store(LVT_MASKED);
+    reg(REG_LVT_LINT1).store(LVT_DELIVERY_NMI);
+    reg(REG_LVT_ERROR).store(LVT_MASKED);
+    reg(REG_SVR).store(SVR_ENABLE | SPURIOUS_VECTOR as u32);
+    eoi();
+}
+
+/// 実行中のプロセッサのLocal APIC IDを返す。
+#[inline]
+pub fn id() -> u8 {
+    (reg(REG_ID).load() >> 24) as u8
+}
+
+/// 割り込みの処理を終えたことを通知する。
+#[inline(always)]
+pub fn eoi() {
+    reg(REG_EOI).store(0);
+}
+
+// Local APIC IDが`dest`のプロセッサにプロセッサ間割り込みを送り、受け付けられるまで待つ
+unsafe fn send_ipi(dest: u8, command: u32) {
+    // 2つのレジスタへの書き込みの間に割り込みで上書きされないようにする
+    let _blocker = IntBlocker::new();
+
+    reg(REG_ICR_HIGH).store((dest as u32) << 24);
+    reg(REG_ICR_LOW).store(command);
+    while reg(REG_ICR_LOW).load() & ICR_PENDING != 0 { }
+}
+
+/// Local APIC IDが`dest`のプロセッサにベクタ`vector`の割り込みを送る。
+#[inline]
+pub fn send_interrupt(dest: u8, vector: u8) {
+    unsafe {
+        send_ipi(dest, vector as u32);
+    }
+}
+
+/// Local APIC IDが`dest`のプロセッサにINITを送り、初期状態に戻す。
+#[inline]
+pub unsafe fn send_init(dest: u8) {
+    send_ipi(dest, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+}
+
+/// Local APIC IDが`dest`のプロセッサに、物理アドレス`page * 0x1000`から
+/// リアルモードで実行を始めるようにStartup IPIを送る。
+#[inline]
+pub unsafe fn send_startup(dest: u8, page: u8) {
+    send_ipi(dest, ICR_DELIVERY_STARTUP | page as u32);
+}
+
+unsafe fn start_periodic() {
+    reg(REG_LVT_TIMER).store(LVT_TIMER_PERIODIC | TIMER_VECTOR as u32);
+    reg(REG_TIMER_INITIAL).store(ticks_per_ms * (1000 / FREQ));
+}
+
+// 溜まった経過時間をミリ秒単位で計上する
+unsafe fn account() {
+    let ms = pending_count / ticks_per_ms as u64;
+    pending_count -= ms * ticks_per_ms as u64;
+    if ms > 0 {
+        timer::manager().tick(ms as usize);
+    }
+}
+
+/// タイマーの周波数をPITで較正し、周期的な割り込みを開始する。
+/// Local APICが有効でない場合は`false`を返す。
+pub unsafe fn init_timer() -> bool {
+    if !enabled {
+        return false;
+    }
+
+    reg(REG_TIMER_DIVIDE).store(TIMER_DIVIDE_BY_16);
+    reg(REG_LVT_TIMER).store(LVT_MASKED);
+    reg(REG_TIMER_INITIAL).store(u32::MAX);
+    let freq = pit::calibrate(|| (u32::MAX - reg(REG_TIMER_CURRENT).load()) as u64);
+    reg(REG_TIMER_INITIAL).store(0);
+
+    if freq < 1000 {
+        return false;
+    }
+    ticks_per_ms = (freq / 1000) as u32;
+    log!("Local APIC timer: {} kHz", ticks_per_ms);
+
+    start_periodic();
+    true
+}
+
+/// 周期的な割り込みを止め、`delay`ミリ秒後に一度だけ割り込みを発生させる。
+/// `delay`が`None`の場合は可能な限り長く待機する。
+///
+/// 割り込みが禁止された状態で呼ぶ必要がある。
+pub fn stop_tick(delay: Option<usize>) {
+    unsafe {
+        let max_ms = (u32::MAX / ticks_per_ms) as usize;
+        let ms = cmp::min(delay.unwrap_or(max_ms), max_ms);
+        if ms <= (1000 / FREQ) as usize {
+            // 周期的な割り込みの方が早い
+            return;
+        }
+
+        // 現在の周期で既に経過した時間
+        let period = ticks_per_ms * (1000 / FREQ);
+        pending_count += (period - cmp::min(reg(REG_TIMER_CURRENT).load(), period)) as u64;
+
+        oneshot_count = ms as u32 * ticks_per_ms;
+        reg(REG_LVT_TIMER).store(TIMER_VECTOR as u32);
+        reg(REG_TIMER_INITIAL).store(oneshot_count);
+    }
+}
+
+/// `stop_tick`で止めた周期的な割り込みを再開し、経過時間を計上する。
+///
+/// 割り込みが禁止された状態で呼ぶ必要がある。
+pub fn restart_tick() {
+    unsafe {
+        if oneshot_count == 0 {
+            return;
+        }
+
+        // 割り込み以外で起床した
+        pending_count += (oneshot_count - cmp::min(reg(REG_TIMER_CURRENT).load(), oneshot_count)) as u64;
+        oneshot_count = 0;
+        start_periodic();
+
+        account();
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lapic_timer_handler() {
+    eoi();
+    if oneshot_count != 0 {
+        // タイマーのコールバックでタスクが切り替わる可能性があるため先に周期モードに戻す
+        pending_count += oneshot_count as u64;
+        oneshot_count = 0;
+        start_periodic();
+
+        account();
+    } else {
+        timer::manager().tick(1000 / FREQ as usize);
+    }
+}
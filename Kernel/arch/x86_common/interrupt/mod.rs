@@ -1,10 +1,16 @@
+use arch::acpi;
+
 pub mod gdt;
 pub mod idt;
 pub mod pic;
 pub mod pit;
+pub mod lapic;
+pub mod ioapic;
+pub mod syscall;
 
 mod a20;
 pub mod device;
+pub mod rtc;
 
 pub const GDT_ENTRY_BOOT_CS:  usize = 2;
 pub const GDT_ENTRY_BOOT_DS:  usize = 3;
@@ -23,6 +29,9 @@ pub const GDT_ENTRIES:                  usize = 18;
 
 pub const KERNEL_CS: usize = GDT_ENTRY_KERNEL_CS * 8;
 pub const KERNEL_DS: usize = GDT_ENTRY_KERNEL_DS * 8;
+pub const USER_CS:   usize = GDT_ENTRY_DEFAULT_USER_CS * 8 | 3;
+pub const USER_DS:   usize = GDT_ENTRY_DEFAULT_USER_DS * 8 | 3;
+pub const TSS:       usize = GDT_ENTRY_TSS * 8;
 
 #[inline(always)]
 pub fn enable() {
@@ -119,10 +128,31 @@ pub fn init() {
         gdt::init();
         idt::init();
         pic::init();
+
+        // APICが使える場合は8259を止めたままにして、I/O APICとLocal APICで割り込みを扱う
+        if acpi::init() && lapic::is_available() && ioapic::is_available() {
+            lapic::init();
+            ioapic::init();
+        } else {
+            log!("APIC is not available, falling back to 8259/PIT");
+        }
+
         pit::init();
         device::init();
+        rtc::init();
 
         self::enable();
     }
 }
 
+/// 他のプロセッサの割り込みを初期化する。`init`の後に、そのプロセッサの上で呼ぶ。
+/// 割り込みは禁止したままにする。
+#[inline]
+pub fn init_secondary() {
+    unsafe {
+        gdt::init_secondary();
+        idt::init_secondary();
+        lapic::init_cpu();
+    }
+}
+
@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 
 use arch::x86_io::{inb, outb};
+use super::{lapic, ioapic};
 
 const PORT_MASTER_PIC_COMMAND: u16 = 0x0020;
 const PORT_MASTER_PIC_STATUS:  u16 = 0x0020;
@@ -110,7 +111,9 @@ impl IRQ {
 
     #[inline]
     pub fn enable(self) {
-        if self.is_master() {
+        if ioapic::is_enabled() {
+            ioapic::unmask(self);
+        } else if self.is_master() {
             enable_port(PORT_MASTER_PIC_IMR, 1 << (self as u8));
         } else {
             enable_port(PORT_SLAVE_PIC_IMR, 1 << (self as u8 - 8));
@@ -120,7 +123,9 @@ impl IRQ {
 
     #[inline]
     pub fn disable(self) {
-        if self.is_master() {
+        if ioapic::is_enabled() {
+            ioapic::mask(self);
+        } else if self.is_master() {
             disable_port(PORT_MASTER_PIC_IMR, 1 << (self as u8));
         } else {
             disable_port(PORT_SLAVE_PIC_IMR, 1 << (self as u8 - 8));
@@ -129,6 +134,11 @@ impl IRQ {
 
     #[inline]
     pub fn eoi(self) {
+        if lapic::is_enabled() {
+            lapic::eoi();
+            return;
+        }
+
         unsafe {
             if self.is_master() {
                 outb(PORT_MASTER_PIC_COMMAND, 0x60 | (self as u8));
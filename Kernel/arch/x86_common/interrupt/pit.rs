@@ -1,8 +1,10 @@
 #![allow(dead_code)]
 
-use arch::x86_io::outb;
+use arch::x86_io::{outb, inb, rdtsc, cpuid};
 use timer;
 use super::pic::IRQ;
+use super::lapic;
+use core::cmp;
 
 const PIT_REG_COUNTER0: u16 = 0x0040;
 const PIT_REG_COUNTER1: u16 = 0x0041;
@@ -41,6 +43,77 @@ const PIT_COM_COUNTER1: u8 = 0x40;
 const PIT_COM_COUNTER2: u8 = 0x80;
 
 const FREQ: u32 = 100;
+const COUNTER: u16 = (PIT_CLOCK / FREQ) as u16;
+const CLOCK_PER_MS: u32 = PIT_CLOCK / 1000;
+// ワンショットで待機できる最大の時間
+const MAX_ONESHOT_MS: usize = 0xFFFF / CLOCK_PER_MS as usize;
+// TSCの較正に使うカウント値(約55ミリ秒)
+const CALIBRATE_COUNT: u16 = 0xFFFF;
+// CPUID(EAX=1)のEDXでTSCが使えることを示すビット
+const CPUID_EDX_TSC: u32 = 1 << 4;
+
+// ワンショットで設定したカウント値。周期モードならば0
+static mut oneshot_count: u16 = 0;
+// まだ計上していない経過時間(PITのクロック数)
+static mut pending_clock: u32 = 0;
+// TSCの周波数(Hz)。TSCが使えない場合は0
+static mut tsc_freq: u64 = 0;
+// 較正を終えた時のTSCの値
+static mut tsc_base: u64 = 0;
+
+#[inline]
+unsafe fn program(mode: u8, count: u16) {
+    outb(PIT_REG_CONTROL, PIT_COM_COUNTER0 | PIT_COM_RL_DATA | mode);
+    outb(PIT_REG_COUNTER0, (count >> 0 & 0xFF) as u8);
+    outb(PIT_REG_COUNTER0, (count >> 8 & 0xFF) as u8);
+}
+
+#[inline]
+unsafe fn read_count() -> u16 {
+    outb(PIT_REG_CONTROL, PIT_COM_COUNTER0 | PIT_COM_RL_LATCH);
+    let lo = inb(PIT_REG_COUNTER0) as u16;
+    let hi = inb(PIT_REG_COUNTER0) as u16;
+    hi << 8 | lo
+}
+
+// 溜まった経過時間をミリ秒単位で計上する
+unsafe fn account() {
+    let ms = pending_clock / CLOCK_PER_MS;
+    pending_clock -= ms * CLOCK_PER_MS;
+    if ms > 0 {
+        timer::manager().tick(ms as usize);
+    }
+}
+
+/// PITのカウンタ0が`CALIBRATE_COUNT`を数える間に`counter`が増えた量から、`counter`の周波数(Hz)を求める。
+///
+/// 割り込みが禁止された状態で呼ぶ必要がある。
+pub unsafe fn calibrate<F: Fn() -> u64>(counter: F) -> u64 {
+    program(PIT_COM_MODE_TERMINAL, CALIBRATE_COUNT);
+    let start = counter();
+    let mut last = CALIBRATE_COUNT;
+    loop {
+        // 0を過ぎると0xFFFFから数え直す
+        let count = read_count();
+        if count > last {
+            break;
+        }
+        last = count;
+    }
+    let end = counter();
+
+    (end - start) * PIT_CLOCK as u64 / (CALIBRATE_COUNT as u64 + 1)
+}
+
+unsafe fn calibrate_tsc() {
+    let (_, _, _, edx) = cpuid(1);
+    if edx & CPUID_EDX_TSC == 0 {
+        return;
+    }
+
+    tsc_freq = calibrate(|| rdtsc());
+    tsc_base = rdtsc();
+}
 
 #[inline(always)]
 pub unsafe fn pre_init() {
@@ -48,19 +121,94 @@ pub unsafe fn pre_init() {
 
 #[inline]
 pub unsafe fn init() {
-    const COUNTER: u16 = (PIT_CLOCK / FREQ) as u16;
-    const COMMAND: u8 = PIT_COM_COUNTER0 | PIT_COM_RL_DATA | PIT_COM_MODE_SQUAREWAVE;
+    calibrate_tsc();
 
-    outb(PIT_REG_CONTROL, COMMAND);
-    outb(PIT_REG_COUNTER0, (COUNTER >> 0 & 0xFF) as u8);
-    outb(PIT_REG_COUNTER0, (COUNTER >> 8 & 0xFF) as u8);
+    // Local APICタイマーが使える場合はそちらをティックに使い、PITの割り込みは止めたままにする
+    if lapic::init_timer() {
+        return;
+    }
+
+    // 経過時間を読み出せるようにRate Generatorを使う
+    program(PIT_COM_MODE_RATEGEN, COUNTER);
 
     super::idt::set_handler(IRQ::PIT, pit_handler);
     IRQ::PIT.enable();
 }
 
+/// 周期的な割り込みを止め、`delay`ミリ秒後に一度だけ割り込みを発生させる。
+/// `delay`が`None`の場合は可能な限り長く待機する。
+///
+/// 割り込みが禁止された状態で呼ぶ必要がある。
+pub fn stop_tick(delay: Option<usize>) {
+    if lapic::is_timer_enabled() {
+        return lapic::stop_tick(delay);
+    }
+
+    let ms = cmp::min(delay.unwrap_or(MAX_ONESHOT_MS), MAX_ONESHOT_MS);
+    if ms <= (1000 / FREQ) as usize {
+        // 周期的な割り込みの方が早い
+        return;
+    }
+
+    unsafe {
+        // 現在の周期で既に経過した時間
+        pending_clock += (COUNTER - read_count()) as u32;
+
+        oneshot_count = (ms as u32 * CLOCK_PER_MS) as u16;
+        program(PIT_COM_MODE_TERMINAL, oneshot_count);
+    }
+}
+
+/// `stop_tick`で止めた周期的な割り込みを再開し、経過時間を計上する。
+///
+/// 割り込みが禁止された状態で呼ぶ必要がある。
+pub fn restart_tick() {
+    if lapic::is_timer_enabled() {
+        return lapic::restart_tick();
+    }
+
+    unsafe {
+        if oneshot_count == 0 {
+            return;
+        }
+
+        // 割り込み以外で起床した
+        pending_clock += (oneshot_count - cmp::min(read_count(), oneshot_count)) as u32;
+        oneshot_count = 0;
+        program(PIT_COM_MODE_RATEGEN, COUNTER);
+
+        account();
+    }
+}
+
 fn pit_handler(_irq: IRQ) {
     IRQ::PIT.eoi();
-    timer::manager().tick(1000 / FREQ as usize);
+    unsafe {
+        if oneshot_count != 0 {
+            // タイマーのコールバックでタスクが切り替わる可能性があるため先に周期モードに戻す
+            pending_clock += oneshot_count as u32;
+            oneshot_count = 0;
+            program(PIT_COM_MODE_RATEGEN, COUNTER);
+
+            account();
+        } else {
+            timer::manager().tick(1000 / FREQ as usize);
+        }
+    }
 }
 
+
+/// 起動時からの経過時間をナノ秒単位で返す。
+///
+/// TSCが使える場合はその分解能で、使えない場合はタイマーの分解能で返す。
+pub fn nanos() -> u64 {
+    unsafe {
+        if tsc_freq == 0 {
+            return timer::manager().counter() * 1000 * 1000;
+        }
+
+        // 乗算で溢れないように秒とそれ未満に分ける
+        let delta = rdtsc() - tsc_base;
+        delta / tsc_freq * 1000 * 1000 * 1000 + delta % tsc_freq * 1000 * 1000 * 1000 / tsc_freq
+    }
+}
@@ -0,0 +1,236 @@
+use arch::x86_io::{outb, inb};
+use sync::SpinLock;
+use event::{self, Event};
+use drivers::Device;
+use drivers::rtc::Rtc;
+use time::DateTime;
+use super::idt;
+use super::pic::IRQ;
+
+const PORT_CMOS_INDEX: u16 = 0x70;
+const PORT_CMOS_DATA:  u16 = 0x71;
+
+const REG_SECOND:        u8 = 0x00;
+const REG_SECOND_ALARM:  u8 = 0x01;
+const REG_MINUTE:        u8 = 0x02;
+const REG_MINUTE_ALARM:  u8 = 0x03;
+const REG_HOUR:          u8 = 0x04;
+const REG_HOUR_ALARM:    u8 = 0x05;
+const REG_DAY:           u8 = 0x07;
+const REG_MONTH:         u8 = 0x08;
+const REG_YEAR:          u8 = 0x09;
+const REG_STATUS_A:      u8 = 0x0A;
+const REG_STATUS_B:      u8 = 0x0B;
+const REG_STATUS_C:      u8 = 0x0C;
+
+// Status Register A
+const STATUS_A_UIP:       u8 = 0x80;
+const STATUS_A_RATE_MASK: u8 = 0x0F;
+
+// Status Register B
+const STATUS_B_PIE:    u8 = 0x40;
+const STATUS_B_AIE:    u8 = 0x20;
+const STATUS_B_24HOUR: u8 = 0x02;
+const STATUS_B_BINARY: u8 = 0x04;
+
+// Status Register C
+const STATUS_C_PF: u8 = 0x40;
+const STATUS_C_AF: u8 = 0x20;
+
+// 時の値で午後を表すビット(12時間表記)
+const HOUR_PM: u8 = 0x80;
+
+// 周期割り込みの基準となる周波数
+const BASE_FREQ: u32 = 32768;
+
+// インデックスとデータのポートを組で使うので、他のプロセッサと同時に操作しないようにする
+static LOCK: SpinLock<()> = SpinLock::new(());
+
+#[inline]
+unsafe fn read_reg(reg: u8) -> u8 {
+    outb(PORT_CMOS_INDEX, reg);
+    inb(PORT_CMOS_DATA)
+}
+
+#[inline]
+unsafe fn write_reg(reg: u8, val: u8) {
+    outb(PORT_CMOS_INDEX, reg);
+    outb(PORT_CMOS_DATA, val);
+}
+
+#[inline]
+fn from_bcd(val: u8) -> u8 {
+    (val >> 4) * 10 + (val & 0x0F)
+}
+
+#[inline]
+fn to_bcd(val: u8) -> u8 {
+    (val / 10) << 4 | val % 10
+}
+
+// 年の下2桁と月から、その月の日数を返す
+fn days_in_month(year: u8, month: u8) -> u8 {
+    match month {
+        2 => if year % 4 == 0 { 29 } else { 28 },
+        4 | 6 | 9 | 11 => 30,
+        _ => 31
+    }
+}
+
+// 更新中でない時に日時のレジスタをまとめて読み出す
+unsafe fn read_raw() -> [u8; 6] {
+    while read_reg(REG_STATUS_A) & STATUS_A_UIP != 0 { }
+    [
+        read_reg(REG_SECOND),
+        read_reg(REG_MINUTE),
+        read_reg(REG_HOUR),
+        read_reg(REG_DAY),
+        read_reg(REG_MONTH),
+        read_reg(REG_YEAR)
+    ]
+}
+
+#[inline]
+pub unsafe fn init() {
+    idt::set_handler(IRQ::CMOSClock, rtc_handler);
+
+    // 溜まっている割り込みを捨てる
+    read_reg(REG_STATUS_C);
+    IRQ::CMOSClock.enable();
+}
+
+/// 現在の日時を読み出す。
+/// 範囲外の値が読み出された場合は、電池切れなどで時刻が壊れているとみなして`None`を返す。
+///
+/// 年は下2桁しか保持されないので、2000年代として扱う。
+pub fn read() -> Option<DateTime> {
+    let _guard = LOCK.lock();
+
+    unsafe {
+        // 読み出しの途中で更新された場合に備え、同じ値が2回続くまで読み直す
+        let mut raw = read_raw();
+        loop {
+            let again = read_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+
+        let status_b = read_reg(REG_STATUS_B);
+        let binary = status_b & STATUS_B_BINARY != 0;
+        let decode = |val: u8| if binary { val } else { from_bcd(val) };
+
+        let pm = raw[2] & HOUR_PM != 0;
+        let mut hour = decode(raw[2] & !HOUR_PM);
+        if status_b & STATUS_B_24HOUR == 0 {
+            if hour < 1 || hour > 12 {
+                return None;
+            }
+            // 12時間表記では0時を12時と表す
+            hour = hour % 12 + if pm { 12 } else { 0 };
+        }
+
+        // 世紀のレジスタは標準の位置が無く、ACPIのFADTで示される機種でしか読めないので使わない
+        let year = decode(raw[5]);
+        let month = decode(raw[4]);
+        let day = decode(raw[3]);
+        let minute = decode(raw[1]);
+        let second = decode(raw[0]);
+        if year > 99 || month < 1 || month > 12 || day < 1 || day > days_in_month(year, month)
+            || hour > 23 || minute > 59 || second > 59 {
+            return None;
+        }
+
+        Some(DateTime {
+            year: 2000 + year as u16,
+            month: month,
+            day: day,
+            hour: hour,
+            minute: minute,
+            second: second
+        })
+    }
+}
+
+/// 周期割り込みを`freq`Hzで発生させ、`Rtc::Periodic`のイベントとして送る。
+/// `freq`は2から8192までの2の累乗でなければならず、それ以外の場合は`false`を返す。
+pub fn set_periodic(freq: u32) -> bool {
+    if freq < 2 || freq > 8192 || !freq.is_power_of_two() {
+        return false;
+    }
+    // 周波数は32768 >> (rate - 1)
+    let rate = (BASE_FREQ / freq).trailing_zeros() as u8 + 1;
+
+    let _guard = LOCK.lock();
+    unsafe {
+        let status_a = read_reg(REG_STATUS_A);
+        write_reg(REG_STATUS_A, status_a & !STATUS_A_RATE_MASK | rate);
+        let status_b = read_reg(REG_STATUS_B);
+        write_reg(REG_STATUS_B, status_b | STATUS_B_PIE);
+    }
+    true
+}
+
+/// 周期割り込みを止める。
+pub fn stop_periodic() {
+    let _guard = LOCK.lock();
+    unsafe {
+        let status_b = read_reg(REG_STATUS_B);
+        write_reg(REG_STATUS_B, status_b & !STATUS_B_PIE);
+    }
+}
+
+/// 毎日`hour`時`minute`分`second`秒に`Rtc::Alarm`のイベントを送る。
+/// 時刻はリアルタイムクロックが保持している時刻(通常は地方時)で、時は24時間表記で指定する。
+/// 範囲外の値はレジスタでは「任意の値に一致する」ことを表す場合があるので、設定せずに`false`を返す。
+pub fn set_alarm(hour: u8, minute: u8, second: u8) -> bool {
+    if hour > 23 || minute > 59 || second > 59 {
+        return false;
+    }
+
+    let _guard = LOCK.lock();
+    unsafe {
+        let status_b = read_reg(REG_STATUS_B);
+        let binary = status_b & STATUS_B_BINARY != 0;
+        let encode = |val: u8| if binary { val } else { to_bcd(val) };
+
+        let hour = if status_b & STATUS_B_24HOUR != 0 {
+            encode(hour)
+        } else {
+            encode(if hour % 12 == 0 { 12 } else { hour % 12 }) | if hour >= 12 { HOUR_PM } else { 0 }
+        };
+
+        write_reg(REG_SECOND_ALARM, encode(second));
+        write_reg(REG_MINUTE_ALARM, encode(minute));
+        write_reg(REG_HOUR_ALARM, hour);
+        write_reg(REG_STATUS_B, status_b | STATUS_B_AIE);
+    }
+    true
+}
+
+/// アラームを止める。
+pub fn clear_alarm() {
+    let _guard = LOCK.lock();
+    unsafe {
+        let status_b = read_reg(REG_STATUS_B);
+        write_reg(REG_STATUS_B, status_b & !STATUS_B_AIE);
+    }
+}
+
+fn rtc_handler(_irq: IRQ) {
+    IRQ::CMOSClock.eoi();
+    unsafe {
+        // 読み出さないと次の割り込みが発生しない
+        let status_c = {
+            let _guard = LOCK.lock();
+            read_reg(REG_STATUS_C)
+        };
+        if status_c & STATUS_C_PF != 0 {
+            event::post_from_isr(Event::Device(Device::Rtc(Rtc::Periodic)));
+        }
+        if status_c & STATUS_C_AF != 0 {
+            event::post_from_isr(Event::Device(Device::Rtc(Rtc::Alarm)));
+        }
+    }
+}
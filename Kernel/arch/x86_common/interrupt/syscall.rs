@@ -0,0 +1,27 @@
+use task;
+use core::u32;
+
+/// システムコールに使う割り込みベクタ
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+/// タスクを終了する。
+pub const SYS_EXIT:  u32 = 0;
+/// 他のタスクに実行を譲る。
+pub const SYS_YIELD: u32 = 1;
+
+// pushaで保存したレジスタのうちeaxの位置
+const REG_EAX: isize = 7;
+
+// eaxに番号を入れてint $0x80で呼ぶ。戻り値はeaxに入る
+#[no_mangle]
+pub unsafe extern "C" fn syscall_handler(regs: *mut u32) {
+    let eax = regs.offset(REG_EAX);
+    *eax = match *eax {
+        SYS_EXIT => task::exit(),
+        SYS_YIELD => {
+            task::yield_now();
+            0
+        },
+        _ => u32::MAX
+    };
+}
@@ -184,3 +184,39 @@ pub unsafe fn save_eflags(eflags: u32)
     asm!("push %eax; popf" :: "{eax}"(eflags) :: "volatile");
 }
 
+
+/// Read the time stamp counter
+#[inline]
+pub unsafe fn rdtsc() -> u64
+{
+    let lo: u32;
+    let hi: u32;
+    asm!("rdtsc" : "={eax}"(lo), "={edx}"(hi) ::: "volatile");
+    (hi as u64) << 32 | lo as u64
+}
+
+/// Execute CPUID and return (eax, ebx, ecx, edx)
+#[inline]
+pub unsafe fn cpuid(leaf: u32) -> (u32, u32, u32, u32)
+{
+    let (a, b, c, d): (u32, u32, u32, u32);
+    asm!("cpuid" : "={eax}"(a), "={ebx}"(b), "={ecx}"(c), "={edx}"(d) : "{eax}"(leaf), "{ecx}"(0) :: "volatile");
+    (a, b, c, d)
+}
+
+/// Read a model specific register
+#[inline]
+pub unsafe fn rdmsr(msr: u32) -> u64
+{
+    let lo: u32;
+    let hi: u32;
+    asm!("rdmsr" : "={eax}"(lo), "={edx}"(hi) : "{ecx}"(msr) :: "volatile");
+    (hi as u64) << 32 | lo as u64
+}
+
+/// Write a model specific register
+#[inline]
+pub unsafe fn wrmsr(msr: u32, val: u64)
+{
+    asm!("wrmsr" :: "{ecx}"(msr), "{eax}"(val as u32), "{edx}"((val >> 32) as u32) :: "volatile");
+}
@@ -0,0 +1,138 @@
+#![allow(dead_code)]
+
+use arch::bios;
+use arch::page::TempMapping;
+use memory::kernel::PhysAddr;
+use core::mem;
+
+// MP Floating Pointer Structureを探す範囲
+const BASE_MEMORY_LAST: u64 = 0x0009FC00;
+const BIOS_AREA_START:  u64 = 0x000F0000;
+
+const FLOATING_SIGNATURE: &'static [u8; 4] = b"_MP_";
+const CONFIG_SIGNATURE:   &'static [u8; 4] = b"PCMP";
+
+// MP Configuration Tableのエントリの種類
+const ENTRY_PROCESSOR: u8 = 0;
+// プロセッサ以外のエントリの長さ
+const ENTRY_OTHER_SIZE: usize = 8;
+
+// Processor Entryのフラグ。プロセッサが使えることを示す
+const PROCESSOR_ENABLED: u8 = 1 << 0;
+
+#[repr(C, packed)]
+struct FloatingPointer {
+    signature:   [u8; 4],
+    config_addr: u32,
+    length:      u8,
+    revision:    u8,
+    checksum:    u8,
+    features:    [u8; 5]
+}
+
+#[repr(C, packed)]
+struct ConfigHeader {
+    signature:      [u8; 4],
+    length:         u16,
+    revision:       u8,
+    checksum:       u8,
+    oem_id:         [u8; 8],
+    product_id:     [u8; 12],
+    oem_table:      u32,
+    oem_table_size: u16,
+    entry_count:    u16,
+    local_apic:     u32,
+    ext_length:     u16,
+    ext_checksum:   u8,
+    reserved:       u8
+}
+
+#[repr(C, packed)]
+struct ProcessorEntry {
+    kind:         u8,
+    apic_id:      u8,
+    apic_version: u8,
+    flags:        u8,
+    signature:    u32,
+    features:     u32,
+    reserved:     [u32; 2]
+}
+
+unsafe fn find_in(start: u64, size: usize) -> Option<&'static FloatingPointer> {
+    bios::scan(start, size, FLOATING_SIGNATURE, 16, |fp: &FloatingPointer| fp.length as usize * 16)
+}
+
+// EBDAの先頭1KB、基本メモリの最後の1KB、BIOSの領域の順に探す
+unsafe fn find_floating_pointer() -> Option<&'static FloatingPointer> {
+    if let Some(ebda) = bios::ebda() {
+        if let Some(fp) = find_in(ebda, bios::EBDA_SEARCH_SIZE) {
+            return Some(fp);
+        }
+    }
+    find_in(BASE_MEMORY_LAST, bios::EBDA_SEARCH_SIZE)
+        .or_else(|| find_in(BIOS_AREA_START, (bios::BIOS_AREA_END - BIOS_AREA_START) as usize))
+}
+
+// 物理アドレス`addr`にあるテーブル全体を一時的に対応付け、チェックサムが正しければ返す
+unsafe fn map_config(addr: u32) -> Option<TempMapping> {
+    let addr = PhysAddr::from_raw(addr as u64);
+    let length = match TempMapping::new(addr, mem::size_of::<ConfigHeader>()) {
+        Some(header) => (*header.as_ptr::<ConfigHeader>()).length as usize,
+        None => return None
+    };
+    if length < mem::size_of::<ConfigHeader>() {
+        return None;
+    }
+
+    let table = match TempMapping::new(addr, length) {
+        Some(table) => table,
+        None => return None
+    };
+    let header = &*table.as_ptr::<ConfigHeader>();
+    if &header.signature == CONFIG_SIGNATURE && bios::checksum(table.as_ptr(), length) {
+        Some(table)
+    } else {
+        None
+    }
+}
+
+/// MPテーブルから使えるプロセッサのLocal APIC IDを読み出して`buf`に格納し、格納した数を返す。
+/// MPテーブルが見つからない場合や、既定の構成を使うことを示している場合は0を返す。
+///
+/// 仮想メモリが有効になってから、他のプロセッサを起動する前に呼ぶ必要がある。
+pub fn cpus(buf: &mut [u8]) -> usize {
+    unsafe {
+        // 読み終えると対応付けを外す
+        let table = match find_floating_pointer() {
+            Some(fp) if fp.config_addr != 0 => match map_config(fp.config_addr) {
+                Some(table) => table,
+                None => return 0
+            },
+            _ => return 0
+        };
+        let header = &*table.as_ptr::<ConfigHeader>();
+
+        let end = header as *const ConfigHeader as usize + header.length as usize;
+        let mut ptr = (header as *const ConfigHeader).offset(1) as usize;
+        let mut count = 0;
+        for _ in 0 .. header.entry_count {
+            if ptr >= end {
+                break;
+            }
+
+            if *(ptr as *const u8) == ENTRY_PROCESSOR {
+                let processor = &*(ptr as *const ProcessorEntry);
+                if processor.flags & PROCESSOR_ENABLED != 0 && count < buf.len() {
+                    buf[count] = processor.apic_id;
+                    count += 1;
+                }
+                ptr += mem::size_of::<ProcessorEntry>();
+            } else {
+                ptr += ENTRY_OTHER_SIZE;
+            }
+        }
+
+        log!("MP table: {} CPU(s)", count);
+        count
+    }
+}
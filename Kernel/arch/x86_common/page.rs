@@ -10,6 +10,10 @@ use core::{u32, usize};
 
 const FRAME_SIZE_ADDR: arch::AddrType = arch::FRAME_SIZE as arch::AddrType;
 
+/// ユーザーモードのタスクに対応付ける仮想アドレスの範囲
+pub const USER_START: usize = 0x40000000;
+pub const USER_END:   usize = 0x80000000;
+
 // TODO: Support PAE
 struct PageDirectoryEntry(u32);
 impl PageDirectoryEntry {
@@ -136,6 +140,10 @@ pub struct PageTable {
 
 impl PageTable {
     pub const FLAGS_KERNEL: (u16, u16) = (PageDirectoryEntry::FLAGS_KERNEL, PageTableEntry::FLAGS_KERNEL);
+    pub const FLAGS_USER:   (u16, u16) = (PageDirectoryEntry::FLAGS_KERNEL | PageDirectoryEntry::FLAG_USER,
+                                          PageTableEntry::FLAGS_KERNEL | PageTableEntry::FLAG_USER);
+    pub const FLAGS_DEVICE: (u16, u16) = (PageDirectoryEntry::FLAGS_KERNEL,
+                                          PageTableEntry::FLAGS_KERNEL | PageTableEntry::FLAG_CACHE_DISABLE);
 
     #[inline(always)]
     pub unsafe fn enable() {
@@ -241,6 +249,11 @@ impl PageTable {
         //    for (pte, pte_addr) in pde.as_slice().iter_mut().zip((pde_addr..).step_by(1 << 12)) {
         for (i, pde) in self.as_slice()[pde_index..].iter_mut().enumerate() {
             let pde_addr = (pde_index + i) << 22;
+            // ユーザー空間はmap_userだけが使う
+            if USER_START <= pde_addr && pde_addr < USER_END {
+                begin_addr = 0;
+                continue;
+            }
             for (j, pte) in pde.as_slice().iter_mut().enumerate() {
                 let pte_addr = pde_addr + (j << 12);
                 if pte.get_flags() & PageTableEntry::FLAG_PRESENT != 0 {
@@ -262,6 +275,47 @@ impl PageTable {
         VirtAddr::null()
     }
 
+    /// ユーザー空間の`virt_addr`に`page`を対応付け、ユーザーモードから読み書きできるようにする。
+    ///
+    /// ページディレクトリの4MB単位でカーネルの対応付けと混ざらないよう、`USER_START`から`USER_END`の範囲に限る。
+    pub fn map_user(&mut self, virt_addr: VirtAddr, page: Shared<PageFrame>, size: usize) {
+        assert!(virt_addr.value() >= USER_START
+                && virt_addr.value().checked_add(size).map_or(false, |end| end <= USER_END));
+        let phys_addr = unsafe {
+            debug_assert!(size <= (**page).size());
+            (**page).addr()
+        };
+        self.map_range(PageTable::FLAGS_USER, virt_addr, phys_addr, size);
+    }
+
+    /// 物理アドレス`phys_addr`から`size`バイトを空いている仮想アドレスに対応付け、その先頭を返す。
+    /// 空きが無い場合はヌルを返す。`phys_addr`と`size`はページ境界に揃っている必要がある。
+    pub fn map_temp(&mut self, flags: (u16, u16), phys_addr: PhysAddr, size: usize) -> VirtAddr {
+        let virt_addr = self.find_free_addr(size);
+        if !virt_addr.is_null() {
+            self.map_range(flags, virt_addr, phys_addr, size);
+        }
+        virt_addr
+    }
+
+    /// `virt_addr`から`size`バイトの対応付けを外す。
+    ///
+    /// 実行中のプロセッサのTLBだけを無効化するので、他のプロセッサが参照していない領域に限る。
+    pub fn unmap(&mut self, virt_addr: VirtAddr, size: usize) {
+        for addr in (virt_addr.value() .. virt_addr.value() + size).step_by(arch::PAGE_SIZE) {
+            let pde = self.get_pde(VirtAddr::from_raw(addr));
+            if pde.get_flags() & PageDirectoryEntry::FLAG_PRESENT == 0 {
+                continue;
+            }
+            let pte = pde.get_pte(VirtAddr::from_raw(addr));
+            pte.set_flags(0);
+            pte.set_address(PhysAddr::null());
+            unsafe {
+                asm!("invlpg ($0)" :: "r"(addr) : "memory" : "volatile");
+            }
+        }
+    }
+
     pub fn map_memory(&mut self, flags: (u16, u16), page: Shared<PageFrame>, size: usize) -> VirtAddr {
         let virt_addr = self.find_free_addr(size);
         let phys_addr = unsafe { (**page).addr() };
@@ -306,3 +360,45 @@ pub fn table() -> &'static mut PageTable {
     }
 }
 
+/// 物理メモリの範囲をカーネルの仮想アドレスに一時的に対応付けたもの。
+/// ファームウェアのテーブルのように、起動時に一度だけ読む領域に使う。
+///
+/// dropすると対応付けを外す。
+pub struct TempMapping {
+    base: VirtAddr,
+    size: usize,
+    offset: usize
+}
+
+impl TempMapping {
+    /// 物理アドレス`addr`から`size`バイトを含むページを対応付ける。
+    /// 仮想アドレスに空きが無い場合は`None`を返す。
+    pub fn new(addr: PhysAddr, size: usize) -> Option<TempMapping> {
+        let start = addr.align_down(FRAME_SIZE_ADDR);
+        let end = PhysAddr::from_raw(addr.value() + size as arch::AddrType).align_up(FRAME_SIZE_ADDR);
+        let map_size = (end - start) as usize;
+
+        let base = table().map_temp(PageTable::FLAGS_KERNEL, start, map_size);
+        if base.is_null() {
+            return None;
+        }
+        Some(TempMapping {
+            base: base,
+            size: map_size,
+            offset: (addr - start) as usize
+        })
+    }
+
+    /// 対応付けた物理アドレス`addr`を指すポインタを返す。
+    #[inline]
+    pub fn as_ptr<T>(&self) -> *const T {
+        (self.base + self.offset).as_ptr()
+    }
+}
+
+impl Drop for TempMapping {
+    fn drop(&mut self) {
+        table().unmap(self.base, self.size);
+    }
+}
+
@@ -0,0 +1,181 @@
+use arch;
+use arch::acpi;
+use arch::mptable;
+use arch::interrupt::{self, lapic};
+use arch::page::{self, PageTable};
+use memory::kernel::PhysAddr;
+use task;
+use time::{self, Duration, Instant};
+use core::mem;
+use core::ptr;
+use core::usize;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use collections::Vec;
+
+/// 扱えるプロセッサの最大数
+pub const MAX_CPUS: usize = 8;
+
+// 他のプロセッサを起動するコードを複写する物理アドレス。smp.Sと合わせる
+const TRAMPOLINE_ADDR: usize = 0x8000;
+// 起動したプロセッサがアイドルタスクとして使うスタックの大きさ
+const AP_STACK_SIZE: usize = 16 * 1024;
+
+extern {
+    static ap_trampoline: u8;
+    static ap_trampoline_end: u8;
+    static ap_boot_cr3: u32;
+    static ap_boot_cr4: u32;
+    static ap_boot_stack: u32;
+    static ap_boot_entry: u32;
+}
+
+// Local APIC IDからプロセッサの番号への対応
+static mut cpu_index: [u8; 256] = [0; 256];
+// プロセッサの番号からLocal APIC IDへの対応
+static mut apic_ids: [u8; MAX_CPUS] = [0; MAX_CPUS];
+// 動作しているプロセッサの数
+static online: AtomicUsize = AtomicUsize::new(1);
+// 起動したプロセッサが初期化を終えたことを知らせる
+static ap_started: AtomicBool = AtomicBool::new(false);
+
+/// 実行中のプロセッサの番号を返す。
+/// 最初に起動したプロセッサが0で、以降は起動した順に番号が付く。
+#[inline]
+pub fn cpu_id() -> usize {
+    if lapic::is_enabled() {
+        unsafe { cpu_index[lapic::id() as usize] as usize }
+    } else {
+        0
+    }
+}
+
+/// 動作しているプロセッサの数を返す。
+#[inline]
+pub fn cpu_count() -> usize {
+    online.load(Ordering::Acquire)
+}
+
+/// `cpu`番のプロセッサにタスクの切り替えを要求する。
+#[inline]
+pub fn reschedule(cpu: usize) {
+    lapic::send_interrupt(unsafe { apic_ids[cpu] }, lapic::RESCHEDULE_VECTOR);
+}
+
+// ACPIのMADTを優先し、無ければMPテーブルからプロセッサを探す
+fn find_cpus(buf: &mut [u8]) -> usize {
+    if let Some(madt) = acpi::madt() {
+        if !madt.cpus().is_empty() {
+            for (dst, &apic_id) in buf.iter_mut().zip(madt.cpus()) {
+                *dst = apic_id;
+            }
+            return madt.cpus().len();
+        }
+    }
+    mptable::cpus(buf)
+}
+
+// 起動用のコードを低位の物理メモリに複写し、同じ仮想アドレスに対応付ける
+unsafe fn setup_trampoline() {
+    let start = &ap_trampoline as *const u8;
+    let size = &ap_trampoline_end as *const u8 as usize - start as usize;
+    page::table().map_direct(PageTable::FLAGS_KERNEL, PhysAddr::from_raw(TRAMPOLINE_ADDR as arch::AddrType), arch::PAGE_SIZE);
+    ptr::copy_nonoverlapping(start, TRAMPOLINE_ADDR as *mut u8, size);
+}
+
+// 複写した起動用のコードの中で`var`に対応する変数を返す
+unsafe fn trampoline_var(var: &u32) -> *mut u32 {
+    let offset = var as *const u32 as usize - &ap_trampoline as *const u8 as usize;
+    (TRAMPOLINE_ADDR + offset) as *mut u32
+}
+
+// INITとStartup IPIを送ってプロセッサを起動し、初期化を終えるまで待つ
+unsafe fn start_cpu(cpu: usize, apic_id: u8) -> bool {
+    cpu_index[apic_id as usize] = cpu as u8;
+    apic_ids[cpu] = apic_id;
+
+    // スタックは解放しない
+    let mut stack: Vec<usize> = Vec::with_capacity(AP_STACK_SIZE / usize::BYTES);
+    let stack_top = stack.as_mut_ptr() as usize + AP_STACK_SIZE;
+    mem::forget(stack);
+
+    let cr3: u32;
+    let cr4: u32;
+    asm!("mov %cr3, $0" : "=r"(cr3) ::: "volatile");
+    asm!("mov %cr4, $0" : "=r"(cr4) ::: "volatile");
+    *trampoline_var(&ap_boot_cr3) = cr3;
+    *trampoline_var(&ap_boot_cr4) = cr4;
+    *trampoline_var(&ap_boot_stack) = stack_top as u32;
+    *trampoline_var(&ap_boot_entry) = ap_main as usize as u32;
+    ap_started.store(false, Ordering::SeqCst);
+
+    lapic::send_init(apic_id);
+    time::delay(Duration::from_millis(10));
+
+    // 1回目で起動しない場合があるので、もう一度送る
+    for _ in 0 .. 2 {
+        lapic::send_startup(apic_id, (TRAMPOLINE_ADDR >> 12) as u8);
+        time::delay(Duration::from_micros(200));
+        if ap_started.load(Ordering::Acquire) {
+            break;
+        }
+    }
+
+    let deadline = Instant::now() + Duration::from_millis(100);
+    while !ap_started.load(Ordering::Acquire) {
+        if Instant::now() >= deadline {
+            return false;
+        }
+    }
+    true
+}
+
+// 起動したプロセッサはsmp.Sからここに来る
+extern "C" fn ap_main() -> ! {
+    interrupt::init_secondary();
+    task::init_secondary();
+    ap_started.store(true, Ordering::Release);
+
+    interrupt::enable();
+    task::idle();
+}
+
+/// 他のプロセッサを起動する。起動したプロセッサはタスクが割り当てられるまで休止する。
+///
+/// `task::init`の後に呼ぶ。Local APICが使えない場合は何もしない。
+pub fn init() {
+    if !lapic::is_enabled() {
+        return;
+    }
+
+    let mut cpus = [0; acpi::MAX_CPUS];
+    let count = find_cpus(&mut cpus);
+
+    unsafe {
+        let bsp = lapic::id();
+        cpu_index[bsp as usize] = 0;
+        apic_ids[0] = bsp;
+
+        setup_trampoline();
+        for &apic_id in cpus[.. count].iter().filter(|&&apic_id| apic_id != bsp) {
+            let cpu = online.load(Ordering::Relaxed);
+            if cpu >= MAX_CPUS {
+                log!("SMP: Ignoring CPUs beyond {}", MAX_CPUS);
+                break;
+            }
+
+            if start_cpu(cpu, apic_id) {
+                online.store(cpu + 1, Ordering::Release);
+            } else {
+                log!("SMP: CPU with APIC ID {} did not start", apic_id);
+            }
+        }
+    }
+
+    log!("SMP: {} CPU(s) online", cpu_count());
+}
+
+#[no_mangle]
+pub extern "C" fn smp_reschedule_handler() {
+    lapic::eoi();
+    task::reschedule();
+}
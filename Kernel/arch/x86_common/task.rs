@@ -1,3 +1,4 @@
+use arch::interrupt::{self, gdt};
 use core::ptr;
 use core::usize;
 use collections::Vec;
@@ -9,6 +10,7 @@ const TASK_STACK_SIZE: usize = 64 * 1024;
 extern "C" {
     fn task_switch(csp: &mut *mut (), cip: &mut *mut (), nsp: *mut (), nip: *mut ());
     fn task_leap(sp: *mut (), ip: *mut ()) -> !;
+    fn task_enter_user(ip: usize, sp: usize, cs: u16, ds: u16) -> !;
 }
 
 pub struct TaskEntity {
@@ -48,6 +50,12 @@ impl TaskEntity {
         // 現在のタスクなので値はどうでもいい
     }
 
+    // ユーザーモードから戻ったときはスタックの末尾から使う
+    #[inline(always)]
+    fn stack_top(&self) -> usize {
+        self.stack.as_ptr() as usize + self.stack.len() * usize::BYTES
+    }
+
     #[inline(always)]
     pub fn terminate(&mut self) {
         self.sp = ptr::null_mut();
@@ -57,11 +65,18 @@ impl TaskEntity {
 
 #[inline]
 pub unsafe fn switch(cur_task: &mut TaskEntity, next_task: &mut TaskEntity) {
+    gdt::set_kernel_stack(next_task.stack_top());
     task_switch(&mut cur_task.sp, &mut cur_task.ip, next_task.sp, next_task.ip);
 }
 
 #[inline]
 pub unsafe fn leap(next_task: &mut TaskEntity) -> ! {
+    gdt::set_kernel_stack(next_task.stack_top());
     task_leap(next_task.sp, next_task.ip);
 }
 
+/// 実行中のタスクをユーザーモードに移し、`stack`をスタックとして`entry`から実行する。
+#[inline]
+pub unsafe fn enter_user(entry: usize, stack: usize) -> ! {
+    task_enter_user(entry, stack, interrupt::USER_CS as u16, interrupt::USER_DS as u16);
+}
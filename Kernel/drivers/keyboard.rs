@@ -72,6 +72,7 @@ impl KeyState {
     }
 }
 
+#[derive(Clone)]
 pub enum Keyboard {
     Down(KeyState),
     Press(KeyState),
@@ -1,10 +1,13 @@
+#[derive(Clone)]
 pub enum Device {
     Keyboard(keyboard::Keyboard),
-    Mouse(mouse::Mouse)
+    Mouse(mouse::Mouse),
+    Rtc(rtc::Rtc)
 }
 
 pub mod keyboard;
 pub mod mouse;
+pub mod rtc;
 
 pub mod display;
 
@@ -1,3 +1,4 @@
+#[derive(Clone)]
 pub struct Mouse {
     pub buttons: [bool; 8],// TODO: bitfields
     pub x: i8,
@@ -0,0 +1,8 @@
+/// リアルタイムクロックの割り込み。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Rtc {
+    /// 周期割り込み。
+    Periodic,
+    /// アラームの時刻になった。
+    Alarm
+}
@@ -1,43 +1,446 @@
-use rt::{Force, ForceRef};
 use lists::RingBuffer;
-use drivers;
+use sync::{SpinLock, WaitQueue};
+use sync::wait_queue;
+use drivers::{self, Device};
+use task::{self, Task};
 use timer;
-use core::ops::{Deref, DerefMut};
+use time::{Duration, Instant};
+use core::mem;
+use core::cell::UnsafeCell;
+use core::any::Any;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::boxed::Box;
+use alloc::arc::Arc;
+use collections::{Vec, BTreeMap};
 
+#[derive(Clone)]
 pub enum Event {
     Timer(timer::TimerId),
-    Device(drivers::Device)
+    Device(drivers::Device),
+    /// アプリケーションが定義したイベント。
+    User(UserEvent)
 }
 
-pub struct EventQueue(RingBuffer<'static, Event>);
+/// アプリケーションが定義したイベント。
+///
+/// `id`で購読するタスクを選び、`payload`または`message`で内容を渡す。
+#[derive(Clone)]
+pub struct UserEvent {
+    /// アプリケーションが決めるイベントの識別子。
+    pub id: usize,
+    /// 任意の値。
+    pub payload: usize,
+    message: Option<Arc<Any + Send + Sync>>
+}
+
+/// キューから取り出したイベントと、それが送られた時刻。
+#[derive(Clone)]
+pub struct Timestamped {
+    pub event: Event,
+    /// イベントが送られた時刻。
+    pub timestamp: Instant
+}
+
+/// 購読するイベントの種類。
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Kind {
+    /// 指定したタイマーの満了。
+    Timer(timer::TimerId),
+    /// キーボードの入力。
+    Keyboard,
+    /// マウスの入力。
+    Mouse,
+    /// リアルタイムクロックの周期割り込みとアラーム。
+    Rtc,
+    /// 指定した識別子のアプリケーションが定義したイベント。
+    User(usize)
+}
+
+/// イベントキューが満杯の時の動作。
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum OverflowPolicy {
+    /// 新しいイベントを捨てる。
+    DropNewest,
+    /// 最も古いイベントを捨てて新しいイベントを加える。
+    DropOldest,
+    /// 連続するマウスの移動を1つのイベントにまとめる。
+    /// まとめられない場合は新しいイベントを捨てる。
+    CoalesceMotion
+}
+
+/// タスクごとのイベントキューの設定。
+#[derive(Clone, Copy, Debug)]
+pub struct QueueConfig {
+    /// 溜められるイベントの数。
+    pub capacity: usize,
+    /// 満杯の時の動作。
+    pub policy: OverflowPolicy
+}
+
+// イベントを購読しているタスクのキュー
+// 割り込みハンドラが書き込み、タスクが読み込む
+// 書き込み側はスケジューラのロックと`QUEUES`のロックを両方保持し、
+// 読み込むのはキューを持つタスクだけで、少なくとも一方のロックを保持するので、`buffer`を同時に操作することは無い
+// 満杯の時に古いイベントを捨てたり最後のイベントをまとめたりするため、ロック無しのリングバッファは使わない
+struct EventQueue {
+    task: Task,
+    kinds: Vec<Kind>,
+    policy: OverflowPolicy,
+    buffer: UnsafeCell<RingBuffer<'static, Timestamped>>,
+    storage: *mut [Timestamped],
+    waiter: WaitQueue,
+    dropped: AtomicUsize
+}
 
 unsafe impl Send for EventQueue { }
-unsafe impl Sync for EventQueue { }
 
-impl Deref for EventQueue {
-    type Target = RingBuffer<'static, Event>;
-    fn deref(&self) -> &RingBuffer<'static, Event> {
-        &self.0
+// タスクのIDごとのキュー
+static QUEUES: SpinLock<Option<BTreeMap<usize, Box<EventQueue>>>> = SpinLock::new(None);
+
+#[allow(non_upper_case_globals)]
+static total_drops: AtomicUsize = AtomicUsize::new(0);
+
+impl Event {
+    /// イベントの種類を返す。
+    pub fn kind(&self) -> Kind {
+        match *self {
+            Event::Timer(id) => Kind::Timer(id),
+            Event::Device(Device::Keyboard(_)) => Kind::Keyboard,
+            Event::Device(Device::Mouse(_)) => Kind::Mouse,
+            Event::Device(Device::Rtc(_)) => Kind::Rtc,
+            Event::User(ref user) => Kind::User(user.id)
+        }
     }
 }
 
-impl DerefMut for EventQueue {
-    fn deref_mut(&mut self) -> &mut RingBuffer<'static, Event> {
-        &mut self.0
+impl UserEvent {
+    /// 値を持つイベントを作る。
+    #[inline]
+    pub fn new(id: usize, payload: usize) -> UserEvent {
+        UserEvent {
+            id: id,
+            payload: payload,
+            message: None
+        }
+    }
+
+    /// 任意の型のメッセージを持つイベントを作る。
+    /// メッセージは購読している全てのタスクから同時に参照されるので、`Sync`である必要がある。
+    ///
+    /// メモリを確保するので、割り込みハンドラでは使えない。
+    pub fn with_message<T: Any + Send + Sync>(id: usize, message: T) -> UserEvent {
+        let message: Arc<Any + Send + Sync> = Arc::new(message);
+        UserEvent {
+            id: id,
+            payload: 0,
+            message: Some(message)
+        }
+    }
+
+    /// メッセージが`T`型ならば参照を返す。
+    pub fn message<T: Any>(&self) -> Option<&T> {
+        self.message.as_ref().and_then(|message| {
+            let message: &Any = &**message;
+            message.downcast_ref::<T>()
+        })
     }
 }
 
-static Q: Force<EventQueue> = Force::new();
-static Q_BUF: Force<[Event; 128]> = Force::new();
+impl QueueConfig {
+    #[inline]
+    pub const fn new(capacity: usize, policy: OverflowPolicy) -> QueueConfig {
+        QueueConfig {
+            capacity: capacity,
+            policy: policy
+        }
+    }
+}
+
+impl Default for QueueConfig {
+    #[inline]
+    fn default() -> QueueConfig {
+        QueueConfig::new(127, OverflowPolicy::DropNewest)
+    }
+}
+
+impl EventQueue {
+    fn new(task: Task, config: QueueConfig) -> EventQueue {
+        // リングバッファは1つ空けておく必要がある
+        let len = (config.capacity + 1).next_power_of_two();
+
+        unsafe {
+            let mut storage = Vec::with_capacity(len);
+            storage.set_len(len);
+            let storage = Box::into_raw(storage.into_boxed_slice());
+
+            EventQueue {
+                task: task,
+                kinds: Vec::new(),
+                policy: config.policy,
+                buffer: UnsafeCell::new(RingBuffer::new(&mut *storage)),
+                storage: storage,
+                waiter: WaitQueue::new(),
+                dropped: AtomicUsize::new(0)
+            }
+        }
+    }
+
+    #[inline]
+    fn buffer(&self) -> &mut RingBuffer<'static, Timestamped> {
+        unsafe { &mut *self.buffer.get() }
+    }
+
+    // スケジューラのロックと`QUEUES`のロックを保持した状態で呼ぶ
+    fn push(&self, event: Timestamped) -> bool {
+        if self.policy == OverflowPolicy::CoalesceMotion && self.coalesce(&event) {
+            return true;
+        }
+
+        let buffer = self.buffer();
+        if buffer.len() < buffer.capacity() {
+            buffer.push(event);
+            return true;
+        }
+
+        self.drop_event();
+        if self.policy == OverflowPolicy::DropOldest {
+            // 最も古いイベントと入れ替える
+            buffer.push(event);
+            true
+        } else {
+            false
+        }
+    }
+
+    // 最後のイベントと同じボタンの状態のマウスの移動ならば移動量を足し合わせる
+    // 時刻はまとめた中で最も新しいものにする
+    fn coalesce(&self, event: &Timestamped) -> bool {
+        let new = match event.event {
+            Event::Device(Device::Mouse(ref mouse)) => mouse,
+            _ => return false
+        };
+
+        let last = match self.buffer().back_mut() {
+            Some(last) => last,
+            None => return false
+        };
+        let coalesced = match last.event {
+            Event::Device(Device::Mouse(ref mut mouse)) if mouse.buttons == new.buttons => {
+                match (mouse.x.checked_add(new.x), mouse.y.checked_add(new.y)) {
+                    (Some(x), Some(y)) => {
+                        mouse.x = x;
+                        mouse.y = y;
+                        true
+                    },
+                    _ => false
+                }
+            },
+            _ => false
+        };
+        if coalesced {
+            last.timestamp = event.timestamp;
+        }
+        coalesced
+    }
+
+    // `Event::Timer(id)`がまだ取り出されずに残っていれば`true`を返す
+    fn has_timer(&self, id: timer::TimerId) -> bool {
+        let buffer = self.buffer();
+        (0..buffer.len()).filter_map(|i| buffer.peek(i)).any(|stamped| {
+            match stamped.event {
+                Event::Timer(t) => t == id,
+                _ => false
+            }
+        })
+    }
+
+    #[inline]
+    fn drop_event(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        total_drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // スケジューラのロックを保持した状態で呼ぶ
+    fn pop_until(&self, deadline: Option<Instant>) -> Option<Timestamped> {
+        loop {
+            if let Some(event) = self.buffer().pop() {
+                return Some(event);
+            }
+            if self.waiter.wait(deadline, || {}).is_err() {
+                return None;
+            }
+        }
+    }
+}
+
+impl Drop for EventQueue {
+    fn drop(&mut self) {
+        while let Some(_) = self.buffer().pop() { }
+        unsafe {
+            // 要素はdropせずに領域だけを解放する
+            Vec::from_raw_parts((*self.storage).as_mut_ptr(), 0, (*self.storage).len());
+        }
+    }
+}
 
 #[inline]
 pub fn init() {
-    let slice = ForceRef::as_mut(Q_BUF.as_ref());
-    *Q.setup() = EventQueue(RingBuffer::new(slice));
+    *QUEUES.lock() = Some(BTreeMap::new());
+}
+
+/// イベントを購読しているタスクのキューに加え、待っているタスクを起こす。
+/// 1つ以上のキューに加えられた場合は`true`を返す。
+#[inline]
+pub fn post(event: Event) -> bool {
+    post_from_isr(event)
+}
+
+/// 割り込みハンドラからイベントを送る。
+/// イベントには呼ばれた時の時刻が記録される。
+///
+/// 1つ以上のキューに加えられた場合は`true`を返す。
+pub fn post_from_isr(event: Event) -> bool {
+    let kind = event.kind();
+    let stamped = Timestamped {
+        event: event,
+        timestamp: Instant::now()
+    };
+    // タスクを起こすので、スケジューラのロックを先に取得する
+    let _guard = task::lock();
+    let queues = QUEUES.lock();
+
+    let mut delivered = false;
+    for queue in queues.as_ref().unwrap().values() {
+        if queue.kinds.contains(&kind) && queue.push(stamped.clone()) {
+            queue.waiter.notify_one();
+            delivered = true;
+        }
+    }
+    delivered
+}
+
+/// タイマーの満了を`Event::Timer(id)`として購読しているタスクに送る。
+/// 前回の満了のイベントがまだ取り出されていないキューには重ねて送らない。
+///
+/// そのようなキューがあった場合は`false`を返す。タイマーの割り込みから呼ばれる。
+pub fn post_timer(id: timer::TimerId) -> bool {
+    let kind = Kind::Timer(id);
+    let stamped = Timestamped {
+        event: Event::Timer(id),
+        timestamp: Instant::now()
+    };
+    let _guard = task::lock();
+    let queues = QUEUES.lock();
+
+    let mut consumed = true;
+    for queue in queues.as_ref().unwrap().values() {
+        if !queue.kinds.contains(&kind) {
+            continue;
+        }
+        if queue.has_timer(id) {
+            consumed = false;
+        } else if queue.push(stamped.clone()) {
+            queue.waiter.notify_one();
+        }
+    }
+    consumed
+}
+
+// 実行中のタスクのキューを返す。無ければ`config`で作る
+fn this_queue<'a>(queues: &'a mut BTreeMap<usize, Box<EventQueue>>, config: QueueConfig) -> &'a mut EventQueue {
+    // 終了したタスクのキューを片付ける
+    let terminated: Vec<usize> = queues.iter().filter(|&(_, queue)| !queue.task.is_valid()).map(|(&id, _)| id).collect();
+    for id in terminated {
+        queues.remove(&id);
+    }
+
+    let this_task = task::this();
+    queues.entry(this_task.id()).or_insert_with(|| Box::new(EventQueue::new(this_task.clone(), config)))
+}
+
+/// 実行中のタスクで`kind`のイベントを購読する。
+/// イベントキューが無ければ既定の設定で作る。
+pub fn subscribe(kind: Kind) {
+    let mut queues = QUEUES.lock();
+    let queue = this_queue(queues.as_mut().unwrap(), QueueConfig::default());
+    if !queue.kinds.contains(&kind) {
+        queue.kinds.push(kind);
+    }
 }
 
-#[inline(always)]
-pub fn queue() -> ForceRef<EventQueue> {
-    Q.as_ref()
+/// 実行中のタスクのイベントキューの容量と満杯の時の動作を設定する。
+/// キューに残っているイベントは容量の範囲で引き継がれる。
+pub fn configure(config: QueueConfig) {
+    let mut queues = QUEUES.lock();
+    let queue = this_queue(queues.as_mut().unwrap(), config);
+
+    let mut new = EventQueue::new(queue.task.clone(), config);
+    mem::swap(&mut new.kinds, &mut queue.kinds);
+    while let Some(event) = queue.buffer().pop() {
+        new.push(event);
+    }
+    new.dropped.store(queue.dropped.load(Ordering::Relaxed), Ordering::Relaxed);
+
+    // 待っているタスクは実行中のタスクだけなので、`waiter`は空である
+    *queue = new;
+}
+
+/// 実行中のタスクで`kind`のイベントの購読をやめる。
+/// 既にキューにあるイベントはそのまま残る。
+pub fn unsubscribe(kind: Kind) {
+    let mut queues = QUEUES.lock();
+    if let Some(queue) = queues.as_mut().unwrap().get_mut(&task::this().id()) {
+        queue.kinds.retain(|&k| k != kind);
+    }
 }
 
+// 実行中のタスクのキューからイベントを取り出す
+fn pop_until(deadline: Option<Instant>) -> Option<Timestamped> {
+    let _guard = task::lock();
+
+    let queue = {
+        let queues = QUEUES.lock();
+        match queues.as_ref().unwrap().get(&task::this().id()) {
+            // キューはタスクが終了するまで取り除かれない
+            Some(queue) => &**queue as *const EventQueue,
+            None => return None
+        }
+    };
+
+    unsafe { (*queue).pop_until(deadline) }
+}
+
+/// 購読しているイベントが届くまでタスクをブロックし、届いたイベントを返す。
+///
+/// # Panics
+/// 何も購読していない場合はpanicする。
+pub fn wait() -> Timestamped {
+    pop_until(None).expect("No events are subscribed")
+}
+
+/// 購読しているイベントが届くか`duration`で指定した時間が経過するまでタスクをブロックする。
+/// 時間が経過した場合は`None`を返す。
+#[inline]
+pub fn wait_timeout(duration: Duration) -> Option<Timestamped> {
+    pop_until(Some(wait_queue::deadline_after(duration)))
+}
+
+/// 実行中のタスクのイベントキューで捨てられたイベントの数を返す。
+pub fn dropped() -> usize {
+    let queues = QUEUES.lock();
+    queues.as_ref().unwrap().get(&task::this().id()).map_or(0, |queue| queue.dropped.load(Ordering::Relaxed))
+}
+
+/// 全てのイベントキューで捨てられたイベントの数を返す。
+#[inline]
+pub fn total_dropped() -> usize {
+    total_drops.load(Ordering::Relaxed)
+}
+
+/// 購読しているイベントが届いていれば取り出す。
+/// 無い場合は即座に`None`を返す。
+pub fn poll() -> Option<Timestamped> {
+    let _guard = task::lock();
+    let queues = QUEUES.lock();
+    queues.as_ref().unwrap().get(&task::this().id()).and_then(|queue| queue.buffer().pop())
+}
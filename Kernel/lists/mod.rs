@@ -1,4 +1,5 @@
 pub use self::ring_buffer::RingBuffer;
+pub use self::spsc_ring_buffer::SpscRingBuffer;
 pub use self::linked_list::{Linker, LinkedNode, DList};
 pub use self::sorted_list::SortedList;
 
@@ -6,6 +7,7 @@ pub use self::sorted_list::SortedList;
 mod macros;
 
 pub mod ring_buffer;
+pub mod spsc_ring_buffer;
 pub mod linked_list;
 pub mod sorted_list;
 
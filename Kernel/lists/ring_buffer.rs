@@ -34,10 +34,20 @@ impl<'a, T> RingBuffer<'a, T> {
     #[inline]
     pub fn is_empty(&self) -> bool { self.read == self.write }
 
+    /// 溜められるデータの数を返す。
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.data.len() - 1
+    }
+
+    /// データを加える。満杯の場合は最も古いデータを捨てる。
     pub fn push(&mut self, value: T) {
         let cur = self.write;
         self.write = self.step(self.write);
         if self.is_empty() {
+            unsafe {
+                ptr::drop_in_place(&mut self.data[self.read]);
+            }
             self.read = self.step(self.read);
         }
 
@@ -46,15 +56,17 @@ impl<'a, T> RingBuffer<'a, T> {
         }
     }
 
+    /// データを加える。満杯の場合は何もせずに`false`を返す。
     pub fn try_push(&mut self, value: T) -> bool {
         let cur = self.write;
-        self.write = self.step(self.write);
-        if self.is_empty() {
+        let next = self.step(cur);
+        if next == self.read {
             false
         } else {
             unsafe {
                 ptr::write(&mut self.data[cur], value);
             }
+            self.write = next;
             true
         }
     }
@@ -82,6 +94,16 @@ impl<'a, T> RingBuffer<'a, T> {
         }
     }
 
+    /// 最後に加えたデータを変更するために参照する。
+    pub fn back_mut(&mut self) -> Option<&mut T> {
+        if self.is_empty() {
+            None
+        } else {
+            let index = self.write.wrapping_sub(1) & (self.data.len() - 1);
+            Some(&mut self.data[index])
+        }
+    }
+
     #[inline]
     fn step(&self, val: usize) -> usize {
         (val + 1) & (self.data.len() - 1)
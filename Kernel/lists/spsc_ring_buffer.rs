@@ -0,0 +1,189 @@
+use core::ptr;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// 1つの書き込み側と1つの読み込み側の間でロック無しにデータを受け渡すリングバッファ。
+///
+/// 書き込み側(例えば割り込みハンドラ)は`try_push`だけを、
+/// 読み込み側(例えばタスク)は`pop`と`peek`だけを呼ぶ限り、割り込みを禁止する必要は無い。
+/// 書き込み側が複数ある場合は、それらが互いに割り込まないようにしなければならない。
+pub struct SpscRingBuffer<'a, T: 'a> {
+    data: UnsafeCell<&'a mut [T]>,
+    read: AtomicUsize,
+    write: AtomicUsize
+}
+
+unsafe impl<'a, T: Send> Send for SpscRingBuffer<'a, T> { }
+unsafe impl<'a, T: Send> Sync for SpscRingBuffer<'a, T> { }
+
+impl<'a, T> SpscRingBuffer<'a, T> {
+    // data.len() should be power of two
+    pub const fn new(data: &'a mut [T]) -> SpscRingBuffer<T> {
+        SpscRingBuffer {
+            data: UnsafeCell::new(data),
+            read: AtomicUsize::new(0),
+            write: AtomicUsize::new(0)
+        }
+    }
+
+    /// 溜まっているデータの数を返す。
+    #[inline]
+    pub fn len(&self) -> usize {
+        let read = self.read.load(Ordering::Acquire);
+        let write = self.write.load(Ordering::Acquire);
+        write.wrapping_sub(read) & self.mask()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.read.load(Ordering::Acquire) == self.write.load(Ordering::Acquire)
+    }
+
+    /// 溜められるデータの数を返す。
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.mask()
+    }
+
+    /// 書き込み側からデータを加える。満杯の場合は`false`を返す。
+    pub fn try_push(&self, value: T) -> bool {
+        let cur = self.write.load(Ordering::Relaxed);
+        let next = self.step(cur);
+        if next == self.read.load(Ordering::Acquire) {
+            return false;
+        }
+
+        unsafe {
+            ptr::write(&mut (*self.data.get())[cur], value);
+        }
+        // データを書き込んでから読み込み側に見せる
+        self.write.store(next, Ordering::Release);
+        true
+    }
+
+    /// 読み込み側からデータを取り出す。
+    pub fn pop(&self) -> Option<T> {
+        let cur = self.read.load(Ordering::Relaxed);
+        if cur == self.write.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let value = unsafe { ptr::read(&(*self.data.get())[cur]) };
+        // データを読み込んでから書き込み側に領域を返す
+        self.read.store(self.step(cur), Ordering::Release);
+        Some(value)
+    }
+
+    /// 読み込み側から先頭より`offset`番目のデータを参照する。
+    pub fn peek(&self, offset: usize) -> Option<&T> {
+        if offset < self.len() {
+            let index = (self.read.load(Ordering::Relaxed) + offset) & self.mask();
+            Some(unsafe { &(*self.data.get())[index] })
+        } else {
+            None
+        }
+    }
+
+    /// 最後に加えたデータを変更するために参照する。
+    ///
+    /// 読み込み側と同時に呼んではならない。
+    pub unsafe fn back_mut(&self) -> Option<&mut T> {
+        let write = self.write.load(Ordering::Relaxed);
+        if self.read.load(Ordering::Acquire) == write {
+            None
+        } else {
+            let index = write.wrapping_sub(1) & self.mask();
+            Some(&mut (*self.data.get())[index])
+        }
+    }
+
+    #[inline]
+    fn mask(&self) -> usize {
+        unsafe { (*self.data.get()).len() - 1 }
+    }
+
+    #[inline]
+    fn step(&self, val: usize) -> usize {
+        (val + 1) & self.mask()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_full_and_empty() {
+        let mut data = [0usize; 4];
+        let buf = SpscRingBuffer::new(&mut data);
+
+        assert_eq!(buf.capacity(), 3);
+        assert!(buf.is_empty());
+        assert_eq!(buf.pop(), None);
+
+        assert!(buf.try_push(1));
+        assert!(buf.try_push(2));
+        assert!(buf.try_push(3));
+        assert!(!buf.try_push(4));
+        assert_eq!(buf.len(), 3);
+
+        assert_eq!(buf.pop(), Some(1));
+        assert_eq!(buf.pop(), Some(2));
+        assert_eq!(buf.pop(), Some(3));
+        assert_eq!(buf.pop(), None);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_wraparound() {
+        let mut data = [0usize; 4];
+        let buf = SpscRingBuffer::new(&mut data);
+
+        for i in 0..9 {
+            assert!(buf.try_push(i));
+            assert!(buf.try_push(i + 100));
+            assert_eq!(buf.len(), 2);
+            assert_eq!(buf.peek(0), Some(&i));
+            assert_eq!(buf.peek(1), Some(&(i + 100)));
+            assert_eq!(buf.peek(2), None);
+            assert_eq!(buf.pop(), Some(i));
+            assert_eq!(buf.pop(), Some(i + 100));
+        }
+        assert!(buf.is_empty());
+
+        // 途中で添字が末尾から先頭に戻るように満杯にする
+        assert!(buf.try_push(7));
+        assert!(buf.try_push(8));
+        assert!(buf.try_push(9));
+        assert!(!buf.try_push(10));
+        assert_eq!(buf.len(), 3);
+        assert_eq!(buf.peek(2), Some(&9));
+    }
+
+    #[test]
+    fn test_back_mut() {
+        let mut data = [0usize; 4];
+        let buf = SpscRingBuffer::new(&mut data);
+
+        unsafe {
+            assert_eq!(buf.back_mut(), None);
+
+            assert!(buf.try_push(1));
+            assert!(buf.try_push(2));
+            *buf.back_mut().unwrap() += 10;
+            assert_eq!(buf.peek(1), Some(&12));
+
+            assert_eq!(buf.pop(), Some(1));
+            assert_eq!(buf.pop(), Some(12));
+            assert_eq!(buf.back_mut(), None);
+
+            // 添字が一周した直後の最後の要素
+            for i in 0..3 {
+                assert!(buf.try_push(i));
+                assert_eq!(buf.pop(), Some(i));
+            }
+            assert!(buf.try_push(5));
+            assert_eq!(buf.back_mut(), Some(&mut 5));
+        }
+    }
+}
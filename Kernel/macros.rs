@@ -25,3 +25,11 @@ macro_rules! debug_log {
     ($($arg:tt)*) => (if cfg!(debug_assertions) { log!($($arg)*); })
 }
 
+
+/// ロックの順序の検証で使うクラスを、呼び出した箇所ごとに1つ作る。
+macro_rules! lock_class {
+    () => ({
+        static CLASS: ::sync::lockdep::Class = ::sync::lockdep::Class::new();
+        &CLASS
+    })
+}
@@ -69,6 +69,8 @@ pub mod event;
 
 pub mod timer;
 
+pub mod time;
+
 pub mod drivers;
 
 // Kernel entrypoint
@@ -92,8 +94,12 @@ pub fn kmain() -> ! {
     event::init();
     timer::init();
     arch::interrupt::init();
+    time::init();
     task::init();
+    spawn_user_test();
+    arch::smp::init();
 
+    log!("Date: {}", time::SystemTime::now().to_datetime());
     log!("Total: {} MB Free: {} MB", memory::buddy::manager().total_size() / 1024 / 1024,
         memory::buddy::manager().free_size() / 1024 / 1024);
 
@@ -114,7 +120,7 @@ pub fn kmain() -> ! {
 
         let a_count = a_count_addr as *mut usize;
 
-        log!("Task-A has launched");
+        log!("Task-A has launched on CPU {}", task::this().cpu());
         loop {
             unsafe {
                 intrinsics::volatile_store(a_count, intrinsics::overflowing_add(intrinsics::volatile_load(a_count), 1));
@@ -122,23 +128,34 @@ pub fn kmain() -> ! {
         }
     });
 
-    let disp_timer = timer::Timer::with_queue(event::queue());
-    disp_timer.reset(1000);
+    // 各プロセッサにタスクが割り当てられることを確かめる
+    for i in 0 .. arch::smp::cpu_count() {
+        task::spawn(move || {
+            log!("Worker {} is running on CPU {}", i, task::this().cpu());
+        });
+    }
+
+    let disp_timer = timer::Timer::with_event();
+    disp_timer.periodic(time::Duration::from_secs(1));
+
+    event::subscribe(event::Kind::Keyboard);
+    event::subscribe(event::Kind::Mouse);
+    event::subscribe(event::Kind::Timer(disp_timer.id()));
 
     //arch::interrupt::wait();
 
     loop {
-        arch::interrupt::disable();
         pri_count.1 += 1;
 
-        match event::queue().pop() {
-            Some(Event::Device(Device::Keyboard(Keyboard::Down(state)))) => {
-                log!("Key down: {:02X}", state.code);
+        let stamped = event::wait();
+        match stamped.event {
+            Event::Device(Device::Keyboard(Keyboard::Down(state))) => {
+                log!("Key down: {:02X} at {:?}", state.code, stamped.timestamp);
             },
-            Some(Event::Device(Device::Keyboard(Keyboard::Press(state)))) => {
+            Event::Device(Device::Keyboard(Keyboard::Press(state))) => {
                 log!("Key press: {:02X}", state.code);
             },
-            Some(Event::Device(Device::Keyboard(Keyboard::Up(state)))) => {
+            Event::Device(Device::Keyboard(Keyboard::Up(state))) => {
                 log!("Key up: {:02X}", state.code);
 
                 match state.code {
@@ -154,7 +171,10 @@ pub fn kmain() -> ! {
                     _ => {}
                 }
             },
-            Some(Event::Device(Device::Mouse(mouse))) => {
+            Event::Device(Device::Rtc(rtc)) => {
+                log!("RTC: {:?}", rtc);
+            },
+            Event::Device(Device::Mouse(mouse)) => {
                 if clicking {
                     clicking = mouse.left();
                 } else if mouse.left() {
@@ -201,7 +221,7 @@ pub fn kmain() -> ! {
                     (color_idx >> 4 & 3) * (0xFF/3)
                 );
             },
-            Some(Event::Timer(timer_id)) => {
+            Event::Timer(timer_id) => {
                 match timer_id {
                     _ if timer_id == disp_timer.id() => {
                         log!("Primary: {}, A: {}", pri_count.1.wrapping_sub(pri_count.0),
@@ -209,16 +229,59 @@ pub fn kmain() -> ! {
                         pri_count.0 = pri_count.1;
                         a_count.0 = a_count.1;
 
-                        disp_timer.reset(1000);
+                        let overruns = disp_timer.take_overruns();
+                        if overruns > 0 {
+                            log!("Display timer overran {} times", overruns);
+                        }
                     },
                     _ => log!("Timer {}", timer_id)
                 }
             },
-            None => {
-                //arch::interrupt::enable_wait();
-                arch::interrupt::enable();
+            Event::User(user) => {
+                log!("User event {}: {}", user.id, user.payload);
             }
         }
     }
 }
 
+// ユーザーモードのタスクを動かし、特権命令で例外が起きてもそのタスクだけが終了することを確かめる
+// バディアロケータとページテーブルはロックで保護されていないので、`arch::smp::init`より前に呼ぶ
+#[cfg(target_arch="x86")]
+fn spawn_user_test() {
+    use arch::page::{self, USER_START};
+    use memory::kernel::VirtAddr;
+    use core::ptr;
+
+    // 2回実行を譲ってから、ユーザーモードでは許されないcliを実行する
+    static USER_CODE: [u8; 17] = [
+        0xB8, 0x01, 0x00, 0x00, 0x00,   // mov $1, %eax
+        0xCD, 0x80,                     // int $0x80
+        0xB8, 0x01, 0x00, 0x00, 0x00,   // mov $1, %eax
+        0xCD, 0x80,                     // int $0x80
+        0xFA,                           // cli
+        0xEB, 0xFE                      // jmp .
+    ];
+
+    // コードとスタックに専用のページを確保し、ユーザー空間に対応付ける
+    let code_addr = VirtAddr::from_raw(USER_START);
+    let stack_addr = VirtAddr::from_raw(USER_START + arch::PAGE_SIZE);
+    let (code_frame, stack_frame) = match (memory::buddy::manager().allocate(0), memory::buddy::manager().allocate(0)) {
+        (Some(code_frame), Some(stack_frame)) => (code_frame, stack_frame),
+        _ => {
+            log!("Unable to allocate pages for a user task");
+            return;
+        }
+    };
+    page::table().map_user(code_addr, code_frame, arch::PAGE_SIZE);
+    page::table().map_user(stack_addr, stack_frame, arch::PAGE_SIZE);
+    unsafe {
+        ptr::copy_nonoverlapping(USER_CODE.as_ptr(), code_addr.as_mut_ptr(), USER_CODE.len());
+    }
+
+    let task = task::spawn_user(code_addr.value(), (stack_addr + arch::PAGE_SIZE).value());
+    log!("User task {} has been spawned", task.id());
+}
+
+#[cfg(not(target_arch="x86"))]
+fn spawn_user_test() {
+}
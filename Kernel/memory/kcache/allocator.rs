@@ -2,6 +2,7 @@ use super::super::buddy;
 use rt::{self, Force, ForceRef};
 use arch;
 use lists::{LinkedNode, DList};
+use sync::SpinLock;
 use core::fmt;
 use core::mem;
 use core::ptr::{self, Unique, Shared};
@@ -27,6 +28,9 @@ pub struct KCacheManager {
 unsafe impl Send for KCacheManager { }
 unsafe impl Sync for KCacheManager { }
 
+// 複数のプロセッサから同時に確保されないように、`list`とバディアロケータの操作を保護する
+static LOCK: SpinLock<()> = SpinLock::new(());
+
 impl KCacheManager {
     #[inline(always)]
     fn init(&mut self) {
@@ -43,6 +47,7 @@ impl KCacheManager {
 
     #[inline]
     fn add<T>(&mut self, allocator: *mut KCacheAllocatorInner<T>) {
+        let _guard = LOCK.lock();
         unsafe {
             self.list.push_back(Shared::new(allocator as *mut KCacheAllocatorInner<()>));
         }
@@ -53,6 +58,7 @@ impl KCacheManager {
     }
 
     pub fn allocate(&mut self, size: usize, align: usize) -> *mut u8 {
+        let _guard = LOCK.lock();
         let alloc_size = rt::align_up(size + 1, align);
         buddy::order_by_size(alloc_size)
             .and_then(|order| buddy::manager().allocate(order))
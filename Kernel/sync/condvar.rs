@@ -0,0 +1,107 @@
+use sync::{LockError, LockResult, MutexGuard};
+use sync::mutex;
+use sync::lockdep::{self, Class};
+use sync::wait_queue::{self, WaitQueue, WaitError};
+use task;
+use time::{Duration, Instant};
+use core::mem;
+
+/// `Mutex`と組み合わせて条件が満たされるまでタスクを待機させる条件変数。
+/// staticに宣言できる。
+pub struct Condvar {
+    waiters: WaitQueue,
+    class: Option<&'static Class>
+}
+
+unsafe impl Send for Condvar { }
+unsafe impl Sync for Condvar { }
+
+impl Condvar {
+    /// 待機しているタスクの無い条件変数を作る。
+    /// ロックとの順序はこの条件変数単独で検証するので、staticに宣言する場合に使う。
+    #[inline(always)]
+    pub const fn new() -> Condvar {
+        Condvar {
+            waiters: WaitQueue::new(),
+            class: None
+        }
+    }
+
+    /// 待機しているタスクの無い条件変数を作る。
+    /// ロックとの順序は`class`が同じ条件変数をまとめて検証する。
+    #[inline(always)]
+    pub const fn with_class(class: &'static Class) -> Condvar {
+        Condvar {
+            waiters: WaitQueue::new(),
+            class: Some(class)
+        }
+    }
+
+    /// `guard`のロックを解除し、通知されるまでタスクをブロックする。
+    /// 起こされた後は再びロックを取得して`guard`を返す。
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> LockResult<MutexGuard<'a, T>> {
+        match self.wait_until(guard, None) {
+            Ok((guard, _)) => Ok(guard),
+            Err(e) => Err(e)
+        }
+    }
+
+    /// `guard`のロックを解除し、通知されるか`duration`で指定した時間が経過するまでタスクをブロックする。
+    /// 起こされた後は再びロックを取得して`guard`を返す。時間が経過した場合は`true`を共に返す。
+    pub fn wait_timeout<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>, duration: Duration) -> LockResult<(MutexGuard<'a, T>, bool)> {
+        self.wait_until(guard, Some(wait_queue::deadline_after(duration)))
+    }
+
+    /// 待機しているタスクを1つ起こす。
+    #[inline]
+    pub fn notify_one(&self) {
+        self.waiters.notify_one();
+    }
+
+    /// 待機している全てのタスクを起こす。
+    #[inline]
+    pub fn notify_all(&self) {
+        self.waiters.notify_all();
+    }
+
+    fn wait_until<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>, deadline: Option<Instant>) -> LockResult<(MutexGuard<'a, T>, bool)> {
+        let lock = mutex::guard_lock(&guard);
+
+        let r = {
+            let _guard = task::lock();
+            self.waiters.wait(deadline, || lock.unlock())
+        };
+
+        // `guard`以外に保持したまま待っていたロックとの順序を記録する
+        lockdep::acquire(self.id(), false);
+        lockdep::release(self.id());
+
+        // `guard`は解除したロックを取得し直してから返す
+        if lock.lock().is_err() {
+            mem::forget(guard);
+            return Err(LockError::Destroyed);
+        }
+
+        match r {
+            Ok(()) => Ok((guard, false)),
+            Err(WaitError::TimedOut) => Ok((guard, true)),
+            Err(WaitError::Destroyed) => Err(LockError::Destroyed)
+        }
+    }
+
+    #[inline(always)]
+    fn id(&self) -> usize {
+        lockdep::key(self.class, self as *const Condvar as usize)
+    }
+}
+
+impl Drop for Condvar {
+    fn drop(&mut self) {
+        if self.class.is_none() {
+            lockdep::forget(self.id());
+        }
+        unsafe {
+            self.waiters.destroy();
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use sync::Lazy;
+use sync::wait_queue;
+use sync::wait_list::WaitList;
+use task;
+use time::{Duration, Instant};
+use core::mem;
+use core::ptr;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// 待機しているタスクを振り分けるキューの数
+const TABLE_LEN: usize = 64;
+
+/// `wait_on`関数のエラーを表す列挙型。
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum WaitOnError {
+    /// 待機する前に値が`expected`と異なっていた。
+    Changed,
+    /// 指定した時間内に起こされなかった。
+    TimedOut
+}
+
+// 各キューには待機しているアドレスを記録し、スケジューラのロックの下で操作する
+struct Table([UnsafeCell<WaitList<usize>>; TABLE_LEN]);
+
+unsafe impl Sync for Table { }
+
+static TABLE: Lazy<Table> = Lazy::new(Table::new);
+
+impl Table {
+    fn new() -> Table {
+        unsafe {
+            let mut table: Table = mem::uninitialized();
+            for bucket in table.0.iter_mut() {
+                ptr::write(bucket, UnsafeCell::new(WaitList::new()));
+            }
+            table
+        }
+    }
+
+    #[inline]
+    fn bucket(&self, addr: usize) -> &mut WaitList<usize> {
+        let word = addr / mem::size_of::<usize>();
+        let index = (word ^ (word >> 6) ^ (word >> 12)) & (TABLE_LEN - 1);
+        unsafe { &mut *self.0[index].get() }
+    }
+}
+
+/// `atom`の値が`expected`である間、`wake`で起こされるまでタスクをブロックする。
+/// `timeout`が`Some`の場合は、その時間が経過すると`WaitOnError::TimedOut`を返す。
+///
+/// 値の確認と待機はアトミックに行われるため、確認の直後に呼ばれた`wake`を見逃すことはない。
+/// 起こされた後に値が変わっている保証は無いので、呼び出し側で確認し直す必要がある。
+pub fn wait_on(atom: &AtomicUsize, expected: usize, timeout: Option<Duration>) -> Result<(), WaitOnError> {
+    let _guard = task::lock();
+
+    if atom.load(Ordering::SeqCst) != expected {
+        return Err(WaitOnError::Changed);
+    }
+
+    let addr = atom as *const AtomicUsize as usize;
+    let this_task = task::this();
+    let q = TABLE.bucket(addr);
+    let ticket = q.push(this_task.clone(), addr);
+    let deadline = timeout.map(wait_queue::deadline_after);
+
+    loop {
+        // `wake`がキューから外した
+        if !q.is_queued(&ticket) {
+            q.remove(ticket);
+            return Ok(());
+        }
+
+        match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if deadline <= now {
+                    q.remove(ticket);
+                    return Err(WaitOnError::TimedOut);
+                }
+                task::sleep(deadline - now);
+            },
+            None => {
+                let _ = this_task.suspend();
+            }
+        }
+    }
+}
+
+/// `atom`で待機しているタスクを最大`n`個起こし、起こしたタスクの数を返す。
+pub fn wake(atom: &AtomicUsize, n: usize) -> usize {
+    let _guard = task::lock();
+
+    let addr = atom as *const AtomicUsize as usize;
+    let q = TABLE.bucket(addr);
+    let mut count = 0;
+    // 終了したタスクは`pop_where`が取り除くので数えない
+    while count < n {
+        match q.pop_where(|&waiter| waiter == addr) {
+            Some(task) => {
+                let _ = task.resume_later();
+                count += 1;
+            },
+            None => break
+        }
+    }
+
+    count
+}
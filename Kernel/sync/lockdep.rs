@@ -0,0 +1,280 @@
+// ロックの取得順序を記録し、デッドロックの可能性を検出する。
+//
+// `--cfg lockdep`でビルドした場合のみ有効となり、それ以外では全ての関数は何もしない。
+// ロックはクラスごとに扱い、クラスは`lock_class!`で作ったstaticな`Class`のアドレスで識別する。
+// 同じ箇所で作られたロックは同じクラスとなるので、動的に作られたロックの順序もまとめて検証できる。
+// クラスを指定せずに作ったロックはインスタンスごとのクラスとして扱い、そのアドレスで識別する。
+// 順序の逆転と再帰的なロックは、実際にデッドロックしなくても初めて検出された時に報告する。
+
+#[cfg(lockdep)]
+use sync::SpinLock;
+#[cfg(lockdep)]
+use task;
+#[cfg(lockdep)]
+use arch;
+#[cfg(lockdep)]
+use collections::{Vec, BTreeMap, BTreeSet};
+
+#[cfg(lockdep)]
+const TRACE_LEN: usize = 8;
+
+/// ロックのクラスを表すキー。
+/// `lock_class!`で作ったstaticへの参照をロックに渡す。
+pub struct Class {
+    // 大きさが0だとstatic同士のアドレスが重なる可能性がある
+    _key: u8
+}
+
+impl Class {
+    /// 新しいクラスを作る。`lock_class!`から使われる。
+    #[inline(always)]
+    pub const fn new() -> Class {
+        Class {
+            _key: 0
+        }
+    }
+}
+
+/// `class`で指定したクラス、または`instance`のアドレスをロックの識別子として返す。
+#[inline(always)]
+pub fn key(class: Option<&'static Class>, instance: usize) -> usize {
+    match class {
+        Some(class) => class as *const Class as usize,
+        None => instance
+    }
+}
+
+#[cfg(lockdep)]
+#[derive(Clone, Copy)]
+struct Trace {
+    frames: [usize; TRACE_LEN],
+    len: usize
+}
+
+// タスクが保持しているロック
+#[cfg(lockdep)]
+struct Held {
+    lock: usize,
+    trace: Trace
+}
+
+// あるロックを保持したまま別のロックを取得した時の記録
+#[cfg(lockdep)]
+struct Dependency {
+    held_trace: Trace,
+    trace: Trace
+}
+
+#[cfg(lockdep)]
+struct Validator {
+    // タスクのIDごとに取得した順に並べる
+    held: BTreeMap<usize, Vec<Held>>,
+    // 先に取得したロックから後に取得したロックへの依存関係
+    dependencies: BTreeMap<usize, BTreeMap<usize, Dependency>>,
+    reported: BTreeSet<(usize, usize)>
+}
+
+#[cfg(lockdep)]
+static VALIDATOR: SpinLock<Option<Validator>> = SpinLock::new(None);
+
+#[cfg(lockdep)]
+impl Trace {
+    #[inline(always)]
+    fn capture() -> Trace {
+        let mut frames = [0; TRACE_LEN];
+        let len = arch::capture_backtrace(&mut frames);
+        Trace {
+            frames: frames,
+            len: len
+        }
+    }
+
+    fn log(&self) {
+        use core::fmt::Write;
+
+        let mut writer = ::logging::Writer::get(module_path!());
+        let _ = write!(&mut writer, "Backtrace:");
+        for frame in &self.frames[..self.len] {
+            let _ = write!(&mut writer, " > {:x}", frame);
+        }
+    }
+}
+
+#[cfg(lockdep)]
+impl Validator {
+    fn new() -> Validator {
+        Validator {
+            held: BTreeMap::new(),
+            dependencies: BTreeMap::new(),
+            reported: BTreeSet::new()
+        }
+    }
+
+    // `from`を保持したまま、直接または間接に`to`を取得したことがあれば、`to`を取得した時の依存関係を返す
+    fn find_path(&self, from: usize, to: usize) -> Option<(usize, &Dependency)> {
+        let mut visited = BTreeSet::new();
+        let mut stack = vec![from];
+
+        while let Some(lock) = stack.pop() {
+            if !visited.insert(lock) {
+                continue;
+            }
+
+            if let Some(next) = self.dependencies.get(&lock) {
+                if let Some(dep) = next.get(&to) {
+                    return Some((lock, dep));
+                }
+                stack.extend(next.keys().cloned());
+            }
+        }
+
+        None
+    }
+
+    fn acquire(&mut self, task_id: usize, lock: usize, trace: Trace, trylock: bool) {
+        let mut held = self.held.remove(&task_id).unwrap_or_else(Vec::new);
+
+        // 取得を試みるだけならばデッドロックしない
+        if !trylock {
+            for h in held.iter() {
+                if h.lock == lock {
+                    if self.reported.insert((lock, lock)) {
+                        log!("lockdep: recursive locking of {:x} in task {}", lock, task_id);
+                        trace.log();
+                        log!("lockdep: previously acquired here");
+                        h.trace.log();
+                    }
+                    continue;
+                }
+
+                let inverted = match self.find_path(lock, h.lock) {
+                    Some((prev, dep)) if !self.reported.contains(&(h.lock, lock)) => {
+                        log!("lockdep: lock order inversion in task {}: acquiring {:x} while holding {:x}", task_id, lock, h.lock);
+                        trace.log();
+                        log!("lockdep: {:x} was acquired here", h.lock);
+                        h.trace.log();
+                        log!("lockdep: previously acquired {:x} while holding {:x}", h.lock, prev);
+                        dep.trace.log();
+                        dep.held_trace.log();
+                        true
+                    },
+                    _ => false
+                };
+                if inverted {
+                    self.reported.insert((h.lock, lock));
+                }
+            }
+
+            for h in held.iter().filter(|h| h.lock != lock) {
+                self.dependencies.entry(h.lock).or_insert_with(BTreeMap::new).entry(lock).or_insert(Dependency {
+                    held_trace: h.trace,
+                    trace: trace
+                });
+            }
+        }
+
+        held.push(Held {
+            lock: lock,
+            trace: trace
+        });
+        self.held.insert(task_id, held);
+    }
+
+    // 記録を消せた場合は`true`を返す
+    fn release(&mut self, task_id: usize, lock: usize) -> bool {
+        let (found, empty) = match self.held.get_mut(&task_id) {
+            Some(held) => {
+                let pos = held.iter().rposition(|h| h.lock == lock);
+                if let Some(pos) = pos {
+                    held.remove(pos);
+                }
+                (pos.is_some(), held.is_empty())
+            },
+            None => (false, false)
+        };
+        if empty {
+            self.held.remove(&task_id);
+        }
+        found
+    }
+
+    fn release_any(&mut self, task_id: usize, lock: usize) {
+        if self.release(task_id, lock) {
+            return;
+        }
+
+        let owner = self.held.iter().find(|&(_, held)| held.iter().any(|h| h.lock == lock)).map(|(&id, _)| id);
+        if let Some(owner) = owner {
+            self.release(owner, lock);
+        }
+    }
+
+    fn forget(&mut self, lock: usize) {
+        self.dependencies.remove(&lock);
+        for next in self.dependencies.values_mut() {
+            next.remove(&lock);
+        }
+        self.reported = self.reported.iter().cloned().filter(|&(a, b)| a != lock && b != lock).collect();
+    }
+}
+
+#[cfg(lockdep)]
+fn with_validator<F: FnOnce(&mut Validator)>(f: F) {
+    let mut validator = VALIDATOR.lock();
+    if validator.is_none() {
+        *validator = Some(Validator::new());
+    }
+    f(validator.as_mut().unwrap());
+}
+
+/// 実行中のタスクが`lock`を取得しようとしていることを記録し、取得順序を検証する。
+/// `trylock`が`true`の場合は、取得に成功したロックとして記録だけを行う。
+#[cfg(lockdep)]
+pub fn acquire(lock: usize, trylock: bool) {
+    let trace = Trace::capture();
+    let task_id = task::this().id();
+    with_validator(|v| v.acquire(task_id, lock, trace, trylock));
+}
+
+/// 実行中のタスクが`lock`を解放したことを記録する。
+#[cfg(lockdep)]
+pub fn release(lock: usize) {
+    let task_id = task::this().id();
+    with_validator(|v| { v.release(task_id, lock); });
+}
+
+/// `lock`が解放されたことを記録する。
+/// 実行中のタスクが保持していなければ、他のタスクが保持している記録を1つ消す。
+/// 取得したタスクとは別のタスクが返却できるセマフォから使われる。
+#[cfg(lockdep)]
+pub fn release_any(lock: usize) {
+    let task_id = task::this().id();
+    with_validator(|v| v.release_any(task_id, lock));
+}
+
+/// 破棄された`lock`の記録を消す。同じアドレスが別のロックに再利用されても誤検出しないようにする。
+/// クラスは他のロックと共有されるので、クラスを持たないロックにだけ使う。
+#[cfg(lockdep)]
+pub fn forget(lock: usize) {
+    with_validator(|v| v.forget(lock));
+}
+
+/// 実行中のタスクが`lock`を取得しようとしていることを記録し、取得順序を検証する。
+#[cfg(not(lockdep))]
+#[inline(always)]
+pub fn acquire(_lock: usize, _trylock: bool) { }
+
+/// 実行中のタスクが`lock`を解放したことを記録する。
+#[cfg(not(lockdep))]
+#[inline(always)]
+pub fn release(_lock: usize) { }
+
+/// `lock`が解放されたことを記録する。
+#[cfg(not(lockdep))]
+#[inline(always)]
+pub fn release_any(_lock: usize) { }
+
+/// 破棄された`lock`の記録を消す。
+#[cfg(not(lockdep))]
+#[inline(always)]
+pub fn forget(_lock: usize) { }
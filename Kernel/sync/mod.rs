@@ -2,7 +2,24 @@ pub use self::sync_queue::SyncQueue;
 pub use self::mutex::{LockError, TryLockForError, TryLockError};
 pub use self::mutex::{LockResult, TryLockForResult, TryLockResult};
 pub use self::mutex::{Mutex, MutexGuard};
+pub use self::wait_queue::WaitQueue;
+pub use self::semaphore::Semaphore;
+pub use self::condvar::Condvar;
+pub use self::rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use self::once::{Once, Lazy};
+pub use self::mpsc::{channel, sync_channel, Sender, SyncSender, Receiver};
+pub use self::spin::{SpinLock, SpinLockGuard, RecursiveSpinLock, RecursiveSpinLockGuard};
+pub use self::futex::{wait_on, wake, WaitOnError};
 
 pub mod sync_queue;
 pub mod mutex;
-
+pub mod wait_queue;
+pub mod wait_list;
+pub mod semaphore;
+pub mod condvar;
+pub mod rwlock;
+pub mod once;
+pub mod mpsc;
+pub mod spin;
+pub mod lockdep;
+pub mod futex;
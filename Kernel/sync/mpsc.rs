@@ -0,0 +1,292 @@
+use sync::wait_queue::{self, WaitQueue, WaitError};
+use task;
+use time::{Duration, Instant};
+use core::cell::UnsafeCell;
+use alloc::arc::Arc;
+use collections::VecDeque;
+
+/// `send`メソッドのエラーを表す列挙型。
+/// 送れなかったデータを含む。
+pub enum SendError<T> {
+    /// `Receiver`が破棄された。
+    Disconnected(T)
+}
+
+/// `try_send`メソッドのエラーを表す列挙型。
+/// 送れなかったデータを含む。
+pub enum TrySendError<T> {
+    /// チャネルが満杯。
+    Full(T),
+    /// `Receiver`が破棄された。
+    Disconnected(T)
+}
+
+/// `recv`メソッドのエラーを表す列挙型。
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum RecvError {
+    /// 全ての`Sender`が破棄され、受け取るデータも無い。
+    Disconnected
+}
+
+/// `recv_timeout`メソッドのエラーを表す列挙型。
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum RecvTimeoutError {
+    /// 指定した時間内にデータが届かなかった。
+    Timeout,
+    /// 全ての`Sender`が破棄され、受け取るデータも無い。
+    Disconnected
+}
+
+/// `try_recv`メソッドのエラーを表す列挙型。
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum TryRecvError {
+    /// 受け取るデータが無い。
+    Empty,
+    /// 全ての`Sender`が破棄され、受け取るデータも無い。
+    Disconnected
+}
+
+// 送信側と受信側で共有するチャネルの状態
+// 全てのフィールドはスケジューラのロックを保持した状態で操作する
+struct Channel<T> {
+    queue: UnsafeCell<VecDeque<T>>,
+    bound: Option<usize>,
+    senders: UnsafeCell<usize>,
+    receiver_alive: UnsafeCell<bool>,
+    // データを待っている`Receiver`
+    receiver_waiter: WaitQueue,
+    // 空きを待っている`SyncSender`
+    sender_waiters: WaitQueue
+}
+
+/// 上限の無いチャネルの送信側。
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>
+}
+
+/// 上限のあるチャネルの送信側。
+pub struct SyncSender<T> {
+    channel: Arc<Channel<T>>
+}
+
+/// チャネルの受信側。
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>
+}
+
+unsafe impl<T: Send> Send for Channel<T> { }
+unsafe impl<T: Send> Sync for Channel<T> { }
+
+/// 上限の無いチャネルを作る。
+/// 送信は常に即座に完了する。
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let channel = Arc::new(Channel::new(None));
+    (Sender { channel: channel.clone() }, Receiver { channel: channel })
+}
+
+/// `bound`個までデータを溜められるチャネルを作る。
+/// 満杯の時の送信は空きができるまでタスクをブロックする。
+///
+/// # Panics
+/// `bound`が0の場合はpanicする。
+pub fn sync_channel<T>(bound: usize) -> (SyncSender<T>, Receiver<T>) {
+    assert!(bound > 0);
+
+    let channel = Arc::new(Channel::new(Some(bound)));
+    (SyncSender { channel: channel.clone() }, Receiver { channel: channel })
+}
+
+impl<T> Channel<T> {
+    fn new(bound: Option<usize>) -> Channel<T> {
+        Channel {
+            queue: UnsafeCell::new(VecDeque::new()),
+            bound: bound,
+            senders: UnsafeCell::new(1),
+            receiver_alive: UnsafeCell::new(true),
+            receiver_waiter: WaitQueue::new(),
+            sender_waiters: WaitQueue::new()
+        }
+    }
+
+    #[inline]
+    fn queue(&self) -> &mut VecDeque<T> {
+        unsafe { &mut *self.queue.get() }
+    }
+
+    #[inline]
+    fn is_full(&self) -> bool {
+        self.bound.map_or(false, |bound| self.queue().len() >= bound)
+    }
+
+    fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
+        let _guard = task::lock();
+
+        if unsafe { !*self.receiver_alive.get() } {
+            return Err(TrySendError::Disconnected(t));
+        }
+        if self.is_full() {
+            return Err(TrySendError::Full(t));
+        }
+
+        self.queue().push_back(t);
+        self.receiver_waiter.notify_one();
+
+        Ok(())
+    }
+
+    fn send(&self, mut t: T) -> Result<(), SendError<T>> {
+        let _guard = task::lock();
+
+        loop {
+            match self.try_send(t) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Disconnected(t)) => return Err(SendError::Disconnected(t)),
+                Err(TrySendError::Full(back)) => t = back
+            }
+
+            // `Receiver`の破棄でも起こされる
+            let _ = self.sender_waiters.wait(None, || {});
+        }
+    }
+
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<T, RecvTimeoutError> {
+        let _guard = task::lock();
+
+        loop {
+            if let Some(t) = self.queue().pop_front() {
+                self.sender_waiters.notify_one();
+                return Ok(t);
+            }
+            if unsafe { *self.senders.get() } == 0 {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+
+            // 全ての`Sender`の破棄でも起こされる
+            match self.receiver_waiter.wait(deadline, || {}) {
+                Err(WaitError::TimedOut) => return Err(RecvTimeoutError::Timeout),
+                _ => {}
+            }
+        }
+    }
+
+    fn add_sender(&self) {
+        let _guard = task::lock();
+        unsafe {
+            *self.senders.get() += 1;
+        }
+    }
+
+    fn drop_sender(&self) {
+        let _guard = task::lock();
+
+        let senders = unsafe { &mut *self.senders.get() };
+        *senders -= 1;
+        if *senders == 0 {
+            self.receiver_waiter.notify_all();
+        }
+    }
+}
+
+impl<T> Sender<T> {
+    /// データを送る。`Receiver`が破棄されている場合はデータを返す。
+    #[inline]
+    pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+        match self.channel.try_send(t) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Disconnected(t)) | Err(TrySendError::Full(t)) => Err(SendError::Disconnected(t))
+        }
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.channel.add_sender();
+        Sender { channel: self.channel.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.channel.drop_sender();
+    }
+}
+
+unsafe impl<T: Send> Send for Sender<T> { }
+
+impl<T> SyncSender<T> {
+    /// データを送る。
+    /// チャネルが満杯の場合は空きができるまでタスクをブロックする。
+    /// `Receiver`が破棄されている場合はデータを返す。
+    #[inline]
+    pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+        self.channel.send(t)
+    }
+
+    /// データの送信を試みる。
+    /// チャネルが満杯の場合は即座に`Err`を返す。
+    #[inline]
+    pub fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
+        self.channel.try_send(t)
+    }
+}
+
+impl<T> Clone for SyncSender<T> {
+    fn clone(&self) -> SyncSender<T> {
+        self.channel.add_sender();
+        SyncSender { channel: self.channel.clone() }
+    }
+}
+
+impl<T> Drop for SyncSender<T> {
+    fn drop(&mut self) {
+        self.channel.drop_sender();
+    }
+}
+
+unsafe impl<T: Send> Send for SyncSender<T> { }
+
+impl<T> Receiver<T> {
+    /// データを受け取る。
+    /// データが無い場合は届くまでタスクをブロックする。
+    pub fn recv(&self) -> Result<T, RecvError> {
+        match self.channel.recv_until(None) {
+            Ok(t) => Ok(t),
+            Err(_) => Err(RecvError::Disconnected)
+        }
+    }
+
+    /// データを受け取る。
+    /// データが無い場合は`duration`で指定した時間が経過するか届くまでタスクをブロックする。
+    #[inline]
+    pub fn recv_timeout(&self, duration: Duration) -> Result<T, RecvTimeoutError> {
+        self.channel.recv_until(Some(wait_queue::deadline_after(duration)))
+    }
+
+    /// データの受け取りを試みる。
+    /// データが無い場合は即座に`Err`を返す。
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let _guard = task::lock();
+
+        match self.channel.queue().pop_front() {
+            Some(t) => {
+                self.channel.sender_waiters.notify_one();
+                Ok(t)
+            },
+            None if unsafe { *self.channel.senders.get() } == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty)
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let _guard = task::lock();
+
+        unsafe {
+            *self.channel.receiver_alive.get() = false;
+        }
+        self.channel.sender_waiters.notify_all();
+    }
+}
+
+unsafe impl<T: Send> Send for Receiver<T> { }
@@ -1,6 +1,9 @@
-use sync::SyncQueue;
-use task::{self, Task};
-use rt::IntBlocker;
+use sync::wait_list::WaitList;
+use sync::lockdep::{self, Class};
+use task::{self, Task, Priority};
+use time::{Duration, Instant};
+use core::cmp;
+use core::ptr;
 use core::cell::UnsafeCell;
 use core::ops::{Deref, DerefMut};
 use core::sync::atomic::{AtomicBool, Ordering};
@@ -27,9 +30,23 @@ pub enum TryLockError {
 
 /// 単純なミューテックス。
 /// staticに宣言できる。
+///
+/// ロックを待っているタスクの優先度が所有者より高い場合、所有者はロックを解除するまでその優先度を継承する。
 pub struct PrimitiveMutex {
     locked: AtomicBool,
-    queue: UnsafeCell<SyncQueue<Task>>
+    queue: UnsafeCell<WaitList<()>>,
+    owner: UnsafeCell<Option<Task>>,
+    // 所有者が保持している次のミューテックス
+    next_held: UnsafeCell<*const PrimitiveMutex>,
+    class: Option<&'static Class>
+}
+
+/// 優先度の継承のためにタスクごとに保持する情報。
+pub struct MutexLink {
+    // ロックを待っているミューテックス
+    blocked_on: *const PrimitiveMutex,
+    // 保持しているミューテックスのリストの先頭
+    held: *const PrimitiveMutex
 }
 
 /// データへの排他的なアクセスを提供するミューテックス。
@@ -59,27 +76,46 @@ unsafe impl Sync for PrimitiveMutex { }
 
 impl PrimitiveMutex {
     /// 未ロック状態で利用可能なミューテックスを作る。
+    /// ロックの順序はこのミューテックス単独で検証するので、staticに宣言する場合に使う。
     #[inline(always)]
     pub const fn new() -> PrimitiveMutex {
         PrimitiveMutex {
             locked: AtomicBool::new(false),
-            queue: UnsafeCell::new(SyncQueue::new())
+            queue: UnsafeCell::new(WaitList::new()),
+            owner: UnsafeCell::new(None),
+            next_held: UnsafeCell::new(ptr::null()),
+            class: None
+        }
+    }
+
+    /// 未ロック状態で利用可能なミューテックスを作る。
+    /// ロックの順序は`class`が同じミューテックスをまとめて検証する。
+    #[inline(always)]
+    pub const fn with_class(class: &'static Class) -> PrimitiveMutex {
+        PrimitiveMutex {
+            locked: AtomicBool::new(false),
+            queue: UnsafeCell::new(WaitList::new()),
+            owner: UnsafeCell::new(None),
+            next_held: UnsafeCell::new(ptr::null()),
+            class: Some(class)
         }
     }
 
     /// ミューテックスをロックする。
     /// 既にロックされている場合はロックが解除されるまでタスクをブロックする。
     pub fn lock(&self) -> LockResult<()> {
-        if self.locked.swap(true, Ordering::Acquire) {
-            let this_task = task::this();
+        let _guard = task::lock();
+        let this_task = task::this();
+        lockdep::acquire(self.id(), false);
 
+        if self.locked.swap(true, Ordering::Acquire) {
             let q = unsafe { &mut *self.queue.get() };
-            let ticket = q.push(this_task.clone());
+            let ticket = q.push(this_task.clone(), ());
+            self.block(&this_task);
 
             // Wait until unlocked
             loop {
-                let _ = task::this().suspend();
-                let _blocker = IntBlocker::new();
+                let _ = this_task.suspend();
 
                 if !self.locked.swap(true, Ordering::SeqCst) {
                     if q.front() == Some(&this_task) {
@@ -88,50 +124,74 @@ impl PrimitiveMutex {
 
                     self.locked.store(false, Ordering::Release);
                 } else {
-                    if !q.contains(&ticket) {
+                    if !q.is_queued(&ticket) {
+                        q.remove(ticket);
+                        this_task.mutex_link().blocked_on = ptr::null();
+                        lockdep::release(self.id());
                         task::yield_now();// Back to a task which is destroying a mutex
                         return Err(LockError::Destroyed);
                     }
                 }
             }
 
-            q.pop();
+            this_task.mutex_link().blocked_on = ptr::null();
+            q.remove(ticket);
         }
 
+        self.set_owner(this_task);
         Ok(())
     }
 
     /// ミューテックスをロックする。
     /// 既にロックされている場合は`duration`で指定した時間が経過するかロックが解除されるまでタスクをブロックする。
-    pub fn try_lock_for(&self, duration: usize) -> TryLockForResult<()> {
-        if self.locked.swap(true, Ordering::Acquire) {
-            let this_task = task::this();
+    pub fn try_lock_for(&self, duration: Duration) -> TryLockForResult<()> {
+        let _guard = task::lock();
+        let this_task = task::this();
+        lockdep::acquire(self.id(), false);
 
+        if self.locked.swap(true, Ordering::Acquire) {
             let q = unsafe { &mut *self.queue.get() };
-            let ticket = q.push(this_task.clone());
-
-            task::sleep(duration);
-            let _blocker = IntBlocker::new();
+            let ticket = q.push(this_task.clone(), ());
+            self.block(&this_task);
 
-            if self.locked.swap(true, Ordering::SeqCst) {
-                if !q.contains(&ticket) {
-                    task::yield_now();// Back to a task which is destroying a mutex
-                    return Err(TryLockForError::Destroyed);
+            // Wait until unlocked or timed out
+            let deadline = Instant::now() + duration;
+            loop {
+                let now = Instant::now();
+                if deadline <= now {
+                    this_task.mutex_link().blocked_on = ptr::null();
+                    q.remove(ticket);
+                    // 継承させた優先度を戻す
+                    self.update_owner_priority();
+                    lockdep::release(self.id());
+                    return Err(TryLockForError::WouldBlock);
                 }
 
-                q.remove(ticket);
-                return Err(TryLockForError::WouldBlock);
-            }
+                // タイマーの満了かロックの解除のどちらか早い方で起こされる
+                task::sleep(deadline - now);
+
+                if !self.locked.swap(true, Ordering::SeqCst) {
+                    if q.front() == Some(&this_task) {
+                        break;
+                    }
 
-            if q.front() != Some(&task::this()) {
-                self.locked.store(false, Ordering::Release);
-                q.remove(ticket);
-                return Err(TryLockForError::WouldBlock);
+                    self.locked.store(false, Ordering::Release);
+                } else {
+                    if !q.is_queued(&ticket) {
+                        q.remove(ticket);
+                        this_task.mutex_link().blocked_on = ptr::null();
+                        lockdep::release(self.id());
+                        task::yield_now();// Back to a task which is destroying a mutex
+                        return Err(TryLockForError::Destroyed);
+                    }
+                }
             }
 
-            q.pop();
+            this_task.mutex_link().blocked_on = ptr::null();
+            q.remove(ticket);
         }
 
+        self.set_owner(this_task);
         Ok(())
     }
 
@@ -139,20 +199,30 @@ impl PrimitiveMutex {
     /// 既にロックされている場合は即座に`false`を返す。
     /// ロックできた場合は`true`を返す。
     pub fn try_lock(&self) -> bool {
-        !self.locked.swap(true, Ordering::SeqCst)
+        let _guard = task::lock();
+
+        if self.locked.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+
+        lockdep::acquire(self.id(), true);
+        self.set_owner(task::this());
+        true
     }
 
     /// ロックされたミューテックスを解除する。
     pub fn unlock(&self) {
+        let _guard = task::lock();
+
         if self.locked.swap(false, Ordering::SeqCst) {
+            lockdep::release(self.id());
+            self.clear_owner();
+
+            // 終了したタスクは`front`が取り除く
+            // タイムアウトで既に起きているタスクは自分でロックを取得する
             let q = unsafe { &mut *self.queue.get() };
-            loop {
-                // タスクの起動に失敗したら次のタスクでやり直し
-                match q.front().map(Task::resume) {
-                    Some(Ok(_)) | None => break,
-                    Some(Err(_)) => {}
-                }
-                q.pop();
+            if let Some(task) = q.front() {
+                let _ = task.resume();
             }
         }
     }
@@ -160,17 +230,131 @@ impl PrimitiveMutex {
     /// ミューテックスを破棄する。
     pub unsafe fn destroy(&mut self) {
         self.locked.store(true, Ordering::Release);
+        if self.class.is_none() {
+            lockdep::forget(self.id());
+        }
+        self.clear_owner();
         let q = &mut *self.queue.get();
-        while let Some(task) = q.pop() {
+        while let Some(task) = q.pop_front() {
             let _ = task.resume_later();
             let _ = task::run_now(&task);
         }
         task::yield_now();
     }
+
+    #[inline(always)]
+    fn id(&self) -> usize {
+        lockdep::key(self.class, self as *const PrimitiveMutex as usize)
+    }
+
+    // ロックを待つタスクとして記録し、所有者に優先度を継承させる
+    fn block(&self, task: &Task) {
+        task.mutex_link().blocked_on = self;
+        self.update_owner_priority();
+    }
+
+    // 待っているタスクの最も高い優先度を返す
+    fn highest_waiter(&self) -> Option<Priority> {
+        let q = unsafe { &*self.queue.get() };
+        q.iter().filter_map(|task| task.priority().ok()).max()
+    }
+
+    // 所有者の優先度を設定し直す。
+    // 所有者も他のミューテックスを待っている場合は、その所有者へ順に伝える。
+    fn update_owner_priority(&self) {
+        let mut mutex = self as *const PrimitiveMutex;
+        while !mutex.is_null() {
+            let owner = match unsafe { (*(*mutex).owner.get()).clone() } {
+                Some(owner) => owner,
+                None => break
+            };
+
+            // 変化が無ければ先の所有者も変わらない
+            match owner.update_priority() {
+                Ok(true) => {},
+                _ => break
+            }
+
+            mutex = owner.mutex_link().blocked_on;
+        }
+    }
+
+    // ロックを取得したタスクを所有者として記録する
+    fn set_owner(&self, task: Task) {
+        unsafe {
+            {
+                let link = task.mutex_link();
+                *self.next_held.get() = link.held;
+                link.held = self;
+            }
+
+            // 残っているタスクの優先度を継承する
+            let _ = task.update_priority();
+            *self.owner.get() = Some(task);
+        }
+    }
+
+    // 所有者の記録を消し、継承した優先度を戻す
+    fn clear_owner(&self) {
+        let owner = match unsafe { (*self.owner.get()).take() } {
+            Some(owner) => owner,
+            None => return
+        };
+        if !owner.is_valid() {
+            return;
+        }
+
+        unsafe {
+            let mut p: *mut *const PrimitiveMutex = &mut owner.mutex_link().held;
+            while !(*p).is_null() {
+                if *p == self as *const PrimitiveMutex {
+                    *p = *self.next_held.get();
+                    break;
+                }
+                p = (**p).next_held.get();
+            }
+        }
+
+        let _ = owner.update_priority();
+    }
+}
+
+impl MutexLink {
+    /// ミューテックスを保持も待機もしていない状態を作る。
+    #[inline]
+    pub const fn new() -> MutexLink {
+        MutexLink {
+            blocked_on: ptr::null(),
+            held: ptr::null()
+        }
+    }
+
+    /// 保持しているミューテックスを待っているタスクの最も高い優先度を返す。
+    pub fn inherited_priority(&self) -> Option<Priority> {
+        let mut priority = None;
+        let mut mutex = self.held;
+        while !mutex.is_null() {
+            unsafe {
+                priority = cmp::max(priority, (*mutex).highest_waiter());
+                mutex = *(*mutex).next_held.get();
+            }
+        }
+        priority
+    }
+
+    /// タスクの優先度が変わった時に呼ばれ、待っているミューテックスの所有者に反映する。
+    pub fn priority_changed(&self) {
+        if !self.blocked_on.is_null() {
+            unsafe {
+                (*self.blocked_on).update_owner_priority();
+            }
+        }
+    }
 }
 
 impl<T> Mutex<T> {
     /// 未ロック状態で利用可能なミューテックスを作る。
+    /// ロックの順序はこのミューテックス単独で検証する。
     #[inline(always)]
     pub fn new(value: T) -> Mutex<T> {
         Mutex {
@@ -178,6 +362,16 @@ impl<T> Mutex<T> {
             data: UnsafeCell::new(value)
         }
     }
+
+    /// 未ロック状態で利用可能なミューテックスを作る。
+    /// `class`には`lock_class!()`を渡し、同じ箇所で作られたミューテックスをまとめてロックの順序を検証する。
+    #[inline(always)]
+    pub fn with_class(value: T, class: &'static Class) -> Mutex<T> {
+        Mutex {
+            inner: PrimitiveMutex::with_class(class),
+            data: UnsafeCell::new(value)
+        }
+    }
 }
 
 impl<T: ?Sized> Mutex<T> {
@@ -190,7 +384,7 @@ impl<T: ?Sized> Mutex<T> {
 
     /// ミューテックスをロックする。
     /// 既にロックされている場合は`duration`で指定した時間が経過するかロックが解除されるまでタスクをブロックする。
-    pub fn try_lock_for(&self, duration: usize) -> TryLockForResult<MutexGuard<T>> {
+    pub fn try_lock_for(&self, duration: Duration) -> TryLockForResult<MutexGuard<T>> {
         try!(self.inner.try_lock_for(duration));
         Ok(MutexGuard::new(&self.inner, &self.data))
     }
@@ -227,6 +421,13 @@ impl<'a, T: ?Sized> MutexGuard<'a, T> {
     }
 }
 
+/// `MutexGuard`がロックしている`PrimitiveMutex`を返す。
+/// `Condvar`から使われる。
+#[inline(always)]
+pub fn guard_lock<'a, T: ?Sized>(guard: &MutexGuard<'a, T>) -> &'a PrimitiveMutex {
+    guard.lock
+}
+
 impl<'a, T: ?Sized> Deref for MutexGuard<'a, T> {
     type Target = T;
 
@@ -0,0 +1,109 @@
+use sync::wait_queue::WaitQueue;
+use task;
+use core::cell::UnsafeCell;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const INCOMPLETE: usize = 0;
+const RUNNING: usize = 1;
+const COMPLETE: usize = 2;
+
+/// 一度だけ初期化処理を実行するためのプリミティブ。
+/// staticに宣言できる。
+pub struct Once {
+    state: AtomicUsize,
+    waiters: WaitQueue
+}
+
+/// 最初にアクセスされた時に値を初期化するコンテナ。
+/// staticに宣言できる。
+pub struct Lazy<T, F = fn() -> T> {
+    once: Once,
+    init: UnsafeCell<Option<F>>,
+    value: UnsafeCell<Option<T>>
+}
+
+unsafe impl Send for Once { }
+unsafe impl Sync for Once { }
+
+impl Once {
+    /// 初期化処理を実行していない`Once`を作る。
+    #[inline(always)]
+    pub const fn new() -> Once {
+        Once {
+            state: AtomicUsize::new(INCOMPLETE),
+            waiters: WaitQueue::new()
+        }
+    }
+
+    /// 初めて呼ばれた時だけ`f`を実行する。
+    /// 他のタスクが`f`を実行している間は完了するまでタスクをブロックする。
+    pub fn call_once<F: FnOnce()>(&self, f: F) {
+        if self.is_completed() {
+            return;
+        }
+
+        {
+            let _guard = task::lock();
+
+            loop {
+                match self.state.load(Ordering::Acquire) {
+                    INCOMPLETE => break,
+                    COMPLETE => return,
+                    // `Once`は破棄されないので起こされるまで待てばよい
+                    _ => { let _ = self.waiters.wait(None, || {}); }
+                }
+            }
+            self.state.store(RUNNING, Ordering::Relaxed);
+        }
+
+        f();
+
+        let _guard = task::lock();
+        self.state.store(COMPLETE, Ordering::Release);
+        self.waiters.notify_all();
+    }
+
+    /// 初期化処理が完了していれば`true`を返す。
+    #[inline]
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+}
+
+unsafe impl<T: Send + Sync, F: Send> Sync for Lazy<T, F> { }
+
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    /// 最初のアクセスで`init`を呼んで初期化する`Lazy`を作る。
+    #[inline(always)]
+    pub const fn new(init: F) -> Lazy<T, F> {
+        Lazy {
+            once: Once::new(),
+            init: UnsafeCell::new(Some(init)),
+            value: UnsafeCell::new(None)
+        }
+    }
+
+    /// 値を初期化して参照を返す。既に初期化されていれば何もしない。
+    pub fn force(this: &Lazy<T, F>) -> &T {
+        this.once.call_once(|| {
+            unsafe {
+                let init = (*this.init.get()).take().unwrap();
+                *this.value.get() = Some(init());
+            }
+        });
+
+        unsafe {
+            (*this.value.get()).as_ref().unwrap()
+        }
+    }
+}
+
+impl<T, F: FnOnce() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        Lazy::force(self)
+    }
+}
@@ -0,0 +1,272 @@
+use sync::{LockError, TryLockForError, TryLockError};
+use sync::{LockResult, TryLockForResult, TryLockResult};
+use sync::wait_queue::{self, WaitQueue, WaitError};
+use sync::lockdep::{self, Class};
+use task;
+use time::{Duration, Instant};
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+// 書き込みロック中の`state`
+const WRITE_LOCKED: isize = -1;
+
+/// 複数の読み込みと排他的な書き込みを提供するロック。
+///
+/// 書き込みを待っているタスクがある間は新しく読み込みロックを取得できない。
+pub struct RwLock<T: ?Sized> {
+    // 読み込みロックの数または`WRITE_LOCKED`
+    state: UnsafeCell<isize>,
+    waiting_writers: UnsafeCell<usize>,
+    readers: WaitQueue,
+    writers: WaitQueue,
+    class: Option<&'static Class>,
+    data: UnsafeCell<T>
+}
+
+/// 読み込みロックをスコープの間だけ保持するRAIIの実装。
+pub struct RwLockReadGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RwLock<T>
+}
+
+/// 書き込みロックをスコープの間だけ保持するRAIIの実装。
+pub struct RwLockWriteGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RwLock<T>
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> { }
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> { }
+
+impl<T> RwLock<T> {
+    /// ロックされていない`RwLock`を作る。
+    /// ロックの順序はこの`RwLock`単独で検証する。
+    #[inline(always)]
+    pub fn new(value: T) -> RwLock<T> {
+        RwLock::with_class_opt(value, None)
+    }
+
+    /// ロックされていない`RwLock`を作る。
+    /// `class`には`lock_class!()`を渡し、同じ箇所で作られた`RwLock`をまとめてロックの順序を検証する。
+    #[inline(always)]
+    pub fn with_class(value: T, class: &'static Class) -> RwLock<T> {
+        RwLock::with_class_opt(value, Some(class))
+    }
+
+    #[inline(always)]
+    fn with_class_opt(value: T, class: Option<&'static Class>) -> RwLock<T> {
+        RwLock {
+            state: UnsafeCell::new(0),
+            waiting_writers: UnsafeCell::new(0),
+            readers: WaitQueue::new(),
+            writers: WaitQueue::new(),
+            class: class,
+            data: UnsafeCell::new(value)
+        }
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// 読み込みロックを取得する。
+    /// 書き込みロックされている場合はロックが解除されるまでタスクをブロックする。
+    pub fn read(&self) -> LockResult<RwLockReadGuard<T>> {
+        match self.read_until(None) {
+            Ok(()) => Ok(RwLockReadGuard { lock: self }),
+            Err(_) => Err(LockError::Destroyed)
+        }
+    }
+
+    /// 読み込みロックを取得する。
+    /// 書き込みロックされている場合は`duration`で指定した時間が経過するかロックが解除されるまでタスクをブロックする。
+    pub fn try_read_for(&self, duration: Duration) -> TryLockForResult<RwLockReadGuard<T>> {
+        match self.read_until(Some(wait_queue::deadline_after(duration))) {
+            Ok(()) => Ok(RwLockReadGuard { lock: self }),
+            Err(WaitError::Destroyed) => Err(TryLockForError::Destroyed),
+            Err(WaitError::TimedOut) => Err(TryLockForError::WouldBlock)
+        }
+    }
+
+    /// 読み込みロックの取得を試みる。
+    /// 書き込みロックされている場合は即座に`Err`を返す。
+    pub fn try_read(&self) -> TryLockResult<RwLockReadGuard<T>> {
+        let _guard = task::lock();
+
+        if self.can_read() {
+            unsafe {
+                *self.state.get() += 1;
+            }
+            lockdep::acquire(self.id(), true);
+            Ok(RwLockReadGuard { lock: self })
+        } else {
+            Err(TryLockError::WouldBlock)
+        }
+    }
+
+    /// 書き込みロックを取得する。
+    /// 既にロックされている場合はロックが全て解除されるまでタスクをブロックする。
+    pub fn write(&self) -> LockResult<RwLockWriteGuard<T>> {
+        match self.write_until(None) {
+            Ok(()) => Ok(RwLockWriteGuard { lock: self }),
+            Err(_) => Err(LockError::Destroyed)
+        }
+    }
+
+    /// 書き込みロックを取得する。
+    /// 既にロックされている場合は`duration`で指定した時間が経過するかロックが全て解除されるまでタスクをブロックする。
+    pub fn try_write_for(&self, duration: Duration) -> TryLockForResult<RwLockWriteGuard<T>> {
+        match self.write_until(Some(wait_queue::deadline_after(duration))) {
+            Ok(()) => Ok(RwLockWriteGuard { lock: self }),
+            Err(WaitError::Destroyed) => Err(TryLockForError::Destroyed),
+            Err(WaitError::TimedOut) => Err(TryLockForError::WouldBlock)
+        }
+    }
+
+    /// 書き込みロックの取得を試みる。
+    /// 既にロックされている場合は即座に`Err`を返す。
+    pub fn try_write(&self) -> TryLockResult<RwLockWriteGuard<T>> {
+        let _guard = task::lock();
+
+        let state = unsafe { &mut *self.state.get() };
+        if *state == 0 {
+            *state = WRITE_LOCKED;
+            lockdep::acquire(self.id(), true);
+            Ok(RwLockWriteGuard { lock: self })
+        } else {
+            Err(TryLockError::WouldBlock)
+        }
+    }
+
+    #[inline(always)]
+    fn id(&self) -> usize {
+        lockdep::key(self.class, self as *const RwLock<T> as *const u8 as usize)
+    }
+
+    #[inline]
+    fn can_read(&self) -> bool {
+        unsafe { *self.state.get() != WRITE_LOCKED && *self.waiting_writers.get() == 0 }
+    }
+
+    fn read_until(&self, deadline: Option<Instant>) -> Result<(), WaitError> {
+        let _guard = task::lock();
+        lockdep::acquire(self.id(), false);
+
+        while !self.can_read() {
+            if let Err(e) = self.readers.wait(deadline, || {}) {
+                lockdep::release(self.id());
+                return Err(e);
+            }
+        }
+        unsafe {
+            *self.state.get() += 1;
+        }
+
+        Ok(())
+    }
+
+    fn write_until(&self, deadline: Option<Instant>) -> Result<(), WaitError> {
+        let _guard = task::lock();
+        lockdep::acquire(self.id(), false);
+
+        let state = unsafe { &mut *self.state.get() };
+        let waiting_writers = unsafe { &mut *self.waiting_writers.get() };
+
+        *waiting_writers += 1;
+        while *state != 0 {
+            if let Err(e) = self.writers.wait(deadline, || {}) {
+                *waiting_writers -= 1;
+                lockdep::release(self.id());
+                if e == WaitError::TimedOut {
+                    // 書き込みを待っていたために止めていた読み込みを再開する
+                    self.wake_next();
+                }
+                return Err(e);
+            }
+        }
+        *waiting_writers -= 1;
+        *state = WRITE_LOCKED;
+
+        Ok(())
+    }
+
+    // ロックを取得できるようになったタスクを起こす
+    fn wake_next(&self) {
+        unsafe {
+            if *self.state.get() != 0 {
+                if *self.state.get() == WRITE_LOCKED || *self.waiting_writers.get() > 0 {
+                    return;
+                }
+                self.readers.notify_all();
+            } else if *self.waiting_writers.get() > 0 {
+                self.writers.notify_one();
+            } else {
+                self.readers.notify_all();
+            }
+        }
+    }
+
+    fn read_unlock(&self) {
+        let _guard = task::lock();
+
+        unsafe {
+            *self.state.get() -= 1;
+        }
+        lockdep::release(self.id());
+        self.wake_next();
+    }
+
+    fn write_unlock(&self) {
+        let _guard = task::lock();
+
+        unsafe {
+            *self.state.get() = 0;
+        }
+        lockdep::release(self.id());
+        self.wake_next();
+    }
+}
+
+impl<T: ?Sized> Drop for RwLock<T> {
+    fn drop(&mut self) {
+        if self.class.is_none() {
+            lockdep::forget(self.id());
+        }
+        unsafe {
+            self.writers.destroy();
+            self.readers.destroy();
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockReadGuard<'a, T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.lock.read_unlock();
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockWriteGuard<'a, T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.lock.write_unlock();
+    }
+}
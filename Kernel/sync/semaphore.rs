@@ -0,0 +1,127 @@
+use sync::{LockError, TryLockForError, TryLockError};
+use sync::{LockResult, TryLockForResult, TryLockResult};
+use sync::wait_queue::{self, WaitQueue, WaitError};
+use sync::lockdep::{self, Class};
+use task;
+use time::{Duration, Instant};
+use core::cell::UnsafeCell;
+
+/// 資源の数を数えるセマフォ。
+/// staticに宣言できる。
+pub struct Semaphore {
+    count: UnsafeCell<usize>,
+    waiters: WaitQueue,
+    class: Option<&'static Class>
+}
+
+unsafe impl Send for Semaphore { }
+unsafe impl Sync for Semaphore { }
+
+impl Semaphore {
+    /// 資源の数を`count`としたセマフォを作る。
+    /// ロックの順序はこのセマフォ単独で検証するので、staticに宣言する場合に使う。
+    #[inline(always)]
+    pub const fn new(count: usize) -> Semaphore {
+        Semaphore {
+            count: UnsafeCell::new(count),
+            waiters: WaitQueue::new(),
+            class: None
+        }
+    }
+
+    /// 資源の数を`count`としたセマフォを作る。
+    /// ロックの順序は`class`が同じセマフォをまとめて検証する。
+    #[inline(always)]
+    pub const fn with_class(count: usize, class: &'static Class) -> Semaphore {
+        Semaphore {
+            count: UnsafeCell::new(count),
+            waiters: WaitQueue::new(),
+            class: Some(class)
+        }
+    }
+
+    /// 資源を1つ取得する。
+    /// 資源が無い場合は`release`されるまでタスクをブロックする。
+    pub fn acquire(&self) -> LockResult<()> {
+        match self.acquire_until(None) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(LockError::Destroyed)
+        }
+    }
+
+    /// 資源を1つ取得する。
+    /// 資源が無い場合は`duration`で指定した時間が経過するか`release`されるまでタスクをブロックする。
+    pub fn try_acquire_for(&self, duration: Duration) -> TryLockForResult<()> {
+        match self.acquire_until(Some(wait_queue::deadline_after(duration))) {
+            Ok(()) => Ok(()),
+            Err(WaitError::Destroyed) => Err(TryLockForError::Destroyed),
+            Err(WaitError::TimedOut) => Err(TryLockForError::WouldBlock)
+        }
+    }
+
+    /// 資源の取得を試みる。
+    /// 資源が無い場合は即座に`Err`を返す。
+    pub fn try_acquire(&self) -> TryLockResult<()> {
+        let _guard = task::lock();
+
+        let count = unsafe { &mut *self.count.get() };
+        if *count == 0 {
+            return Err(TryLockError::WouldBlock);
+        }
+        *count -= 1;
+        lockdep::acquire(self.id(), true);
+
+        Ok(())
+    }
+
+    /// 資源を1つ返却し、待っているタスクがあれば起こす。
+    pub fn release(&self) {
+        let _guard = task::lock();
+
+        unsafe {
+            *self.count.get() += 1;
+        }
+        // 取得したタスクとは別のタスクが返却してもよい
+        lockdep::release_any(self.id());
+        self.waiters.notify_one();
+    }
+
+    /// 現在の資源の数を返す。
+    #[inline]
+    pub fn count(&self) -> usize {
+        unsafe { *self.count.get() }
+    }
+
+    fn acquire_until(&self, deadline: Option<Instant>) -> Result<(), WaitError> {
+        let _guard = task::lock();
+
+        lockdep::acquire(self.id(), false);
+
+        let count = unsafe { &mut *self.count.get() };
+        while *count == 0 {
+            if let Err(e) = self.waiters.wait(deadline, || {}) {
+                lockdep::release(self.id());
+                return Err(e);
+            }
+        }
+        *count -= 1;
+
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn id(&self) -> usize {
+        lockdep::key(self.class, self as *const Semaphore as usize)
+    }
+}
+
+impl Drop for Semaphore {
+    fn drop(&mut self) {
+        if self.class.is_none() {
+            lockdep::forget(self.id());
+        }
+        unsafe {
+            self.waiters.destroy();
+        }
+    }
+}
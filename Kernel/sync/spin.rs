@@ -0,0 +1,213 @@
+use rt::IntBlocker;
+use arch::smp;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// 割り込みを禁止してから取得するスピンロック。
+/// staticに宣言できる。
+///
+/// ロックを保持している間は割り込みが禁止されるため、
+/// タスクと割り込みハンドラの間で共有してもデッドロックしない。
+/// タスクをブロックしないので、割り込みハンドラからも使える。
+pub struct SpinLock<T: ?Sized> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>
+}
+
+/// スコープの間だけスピンロックを保持するRAIIの実装。
+/// この構造体がdropされるとロックを解除し、割り込みの状態を元に戻す。
+pub struct SpinLockGuard<'a, T: ?Sized + 'a> {
+    lock: &'a SpinLock<T>,
+    // ロックの解除後に割り込みの状態を戻す
+    _blocker: IntBlocker
+}
+
+unsafe impl<T: ?Sized + Send> Send for SpinLock<T> { }
+unsafe impl<T: ?Sized + Send> Sync for SpinLock<T> { }
+
+impl<T> SpinLock<T> {
+    /// 未ロック状態のスピンロックを作る。
+    #[inline(always)]
+    pub const fn new(value: T) -> SpinLock<T> {
+        SpinLock {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(value)
+        }
+    }
+}
+
+impl<T: ?Sized> SpinLock<T> {
+    /// 割り込みを禁止し、ロックを取得できるまで待つ。
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        let blocker = IntBlocker::new();
+
+        while self.locked.compare_and_swap(false, true, Ordering::Acquire) {
+            while self.locked.load(Ordering::Relaxed) { }
+        }
+
+        SpinLockGuard {
+            lock: self,
+            _blocker: blocker
+        }
+    }
+
+    /// ロックの取得を試みる。
+    /// 既にロックされている場合は即座に`None`を返す。
+    pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
+        let blocker = IntBlocker::new();
+
+        if self.locked.compare_and_swap(false, true, Ordering::Acquire) {
+            None
+        } else {
+            Some(SpinLockGuard {
+                lock: self,
+                _blocker: blocker
+            })
+        }
+    }
+
+    /// ロックされていれば`true`を返す。
+    #[inline]
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+}
+
+impl<'a, T: ?Sized> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for SpinLockGuard<'a, T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
+
+/// 同じプロセッサからは重ねて取得できるスピンロック。
+/// staticに宣言できる。
+///
+/// 所有者はタスクではなくプロセッサで判定するので、保持したままタスクを切り替えられる。
+/// 切り替え先のタスクは`set_depth`で自分の深さに戻すか、`release_all`で解除する。
+/// スケジューラのように、タスクの切り替えを含む処理を他のプロセッサから守るために使う。
+pub struct RecursiveSpinLock {
+    // 保持しているプロセッサの番号に1を足した値。0ならば未ロック
+    owner: AtomicUsize,
+    // 保持しているプロセッサからのみ読み書きする
+    depth: UnsafeCell<usize>
+}
+
+/// スコープの間だけ`RecursiveSpinLock`を保持するRAIIの実装。
+/// この構造体がdropされるとロックを1段解除し、割り込みの状態を元に戻す。
+pub struct RecursiveSpinLockGuard<'a> {
+    lock: &'a RecursiveSpinLock,
+    // ロックの解除後に割り込みの状態を戻す
+    _blocker: IntBlocker
+}
+
+unsafe impl Send for RecursiveSpinLock { }
+unsafe impl Sync for RecursiveSpinLock { }
+
+impl RecursiveSpinLock {
+    /// 未ロック状態のスピンロックを作る。
+    #[inline(always)]
+    pub const fn new() -> RecursiveSpinLock {
+        RecursiveSpinLock {
+            owner: AtomicUsize::new(0),
+            depth: UnsafeCell::new(0)
+        }
+    }
+
+    /// 割り込みを禁止し、ロックを取得できるまで待つ。
+    /// 実行中のプロセッサが既に保持している場合は深さを1つ増やす。
+    pub fn lock(&self) -> RecursiveSpinLockGuard {
+        let blocker = IntBlocker::new();
+        unsafe {
+            self.acquire();
+        }
+
+        RecursiveSpinLockGuard {
+            lock: self,
+            _blocker: blocker
+        }
+    }
+
+    /// ガードを作らずにロックを取得する。
+    /// 割り込みが禁止された状態で呼ばなければならない。
+    pub unsafe fn acquire(&self) {
+        let me = smp::cpu_id() + 1;
+        if self.owner.load(Ordering::Relaxed) == me {
+            *self.depth.get() += 1;
+            return;
+        }
+
+        while self.owner.compare_and_swap(0, me, Ordering::Acquire) != 0 {
+            while self.owner.load(Ordering::Relaxed) != 0 { }
+        }
+        *self.depth.get() = 1;
+    }
+
+    /// `acquire`で取得したロックを1段解除する。
+    pub unsafe fn release(&self) {
+        let depth = self.depth.get();
+        *depth -= 1;
+        if *depth == 0 {
+            self.owner.store(0, Ordering::Release);
+        }
+    }
+
+    /// 実行中のプロセッサが保持している深さを返す。
+    #[inline]
+    pub unsafe fn depth(&self) -> usize {
+        *self.depth.get()
+    }
+
+    /// 保持している深さを`depth`に設定する。
+    /// ロックを保持したままタスクを切り替えた後、切り替え前の深さに戻すために使う。
+    #[inline]
+    pub unsafe fn set_depth(&self, depth: usize) {
+        *self.depth.get() = depth;
+    }
+
+    /// 保持している深さに関わらずロックを解除し、解除前の深さを返す。
+    /// 割り込みが禁止された状態で呼ばなければならない。
+    pub unsafe fn release_all(&self) -> usize {
+        let depth = *self.depth.get();
+        *self.depth.get() = 0;
+        self.owner.store(0, Ordering::Release);
+        depth
+    }
+
+    /// `release_all`で解除したロックを取得し直し、深さを`depth`に戻す。
+    /// 割り込みが禁止された状態で呼ばなければならない。
+    pub unsafe fn reacquire(&self, depth: usize) {
+        self.acquire();
+        *self.depth.get() = depth;
+    }
+
+    /// 実行中のプロセッサが保持していれば`true`を返す。
+    #[inline]
+    pub fn is_held(&self) -> bool {
+        self.owner.load(Ordering::Relaxed) == smp::cpu_id() + 1
+    }
+}
+
+impl<'a> Drop for RecursiveSpinLockGuard<'a> {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe {
+            self.lock.release();
+        }
+    }
+}
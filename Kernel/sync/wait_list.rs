@@ -0,0 +1,148 @@
+use lists::{DList, LinkedNode, Linker};
+use lists::linked_list;
+use task::Task;
+use core::marker::PhantomData;
+use core::ptr::Shared;
+use alloc::boxed::Box;
+
+/// 待機しているタスクとそのデータを並べるリスト。
+///
+/// 全ての操作はスケジューラのロック(`task::lock`)を保持した状態で行う。
+/// ノードは待機する側が`push`で確保し、起こされた後か諦めた時に`remove`で解放する。
+/// 起こす側はノードをリストから外すだけで、終了したタスクのノードだけをその場で解放する。
+pub struct WaitList<T> {
+    list: DList<WaitNode<T>>
+}
+
+/// `WaitList`のノード。
+pub struct WaitNode<T> {
+    task: Task,
+    data: T,
+    queued: bool,
+    prev: Option<Shared<WaitNode<T>>>,
+    next: Option<Shared<WaitNode<T>>>
+}
+
+impl<T> LinkedNode for WaitNode<T> {
+    linked_node!(Shared<WaitNode<T>> { prev: prev, next: next });
+}
+
+/// `push`で加えたノードを指す。待機する側が保持し、`remove`に渡して解放する。
+pub struct WaitTicket<T>(Shared<WaitNode<T>>);
+
+/// リストのデータを先頭から順に返すイテレータ。
+pub struct Iter<'a, T: 'a> {
+    iter: linked_list::Iter<WaitNode<T>>,
+    _marker: PhantomData<&'a T>
+}
+
+impl<T> WaitList<T> {
+    /// 空の`WaitList`を作る。
+    #[inline(always)]
+    pub const fn new() -> WaitList<T> {
+        WaitList {
+            list: DList::new()
+        }
+    }
+
+    /// 待機しているタスクが無ければ`true`を返す。
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    /// `task`とそのデータを末尾に加える。
+    pub fn push(&mut self, task: Task, data: T) -> WaitTicket<T> {
+        let node = Box::new(WaitNode {
+            task: task,
+            data: data,
+            queued: true,
+            prev: None,
+            next: None
+        });
+        let node = unsafe { Shared::new(Box::into_raw(node)) };
+        self.list.push_back(node);
+        WaitTicket(node)
+    }
+
+    /// `ticket`のノードがまだリストに含まれていれば`true`を返す。
+    #[inline]
+    pub fn is_queued(&self, ticket: &WaitTicket<T>) -> bool {
+        ticket.0.as_ref().queued
+    }
+
+    /// `ticket`のノードのデータを返す。
+    #[inline]
+    pub fn get(&self, ticket: &WaitTicket<T>) -> &T {
+        unsafe { &(**ticket.0).data }
+    }
+
+    /// `ticket`のノードをリストに含まれていれば外し、解放してデータを返す。
+    /// 起こす側によって既に外されていても、リストのどの位置にあってもよい。
+    pub fn remove(&mut self, ticket: WaitTicket<T>) -> T {
+        let WaitTicket(node) = ticket;
+        if node.as_ref().queued {
+            self.list.remove(&node);
+        }
+        unsafe { Box::from_raw(*node).data }
+    }
+
+    /// 先頭のノードのタスクを返す。終了したタスクのノードは先に解放する。
+    pub fn front(&mut self) -> Option<&Task> {
+        self.purge_front();
+        self.list.front().map(|node| unsafe { &(**node).task })
+    }
+
+    /// 先頭のノードを外し、そのタスクを返す。
+    pub fn pop_front(&mut self) -> Option<Task> {
+        self.pop_where(|_| true)
+    }
+
+    /// `pred`を満たす最初のノードを外し、そのタスクを返す。
+    /// 途中で見つけた終了したタスクのノードは、条件に関わらず外して解放する。
+    pub fn pop_where<F: FnMut(&T) -> bool>(&mut self, mut pred: F) -> Option<Task> {
+        let mut cur = self.list.front();
+        while let Some(mut node) = cur {
+            cur = node.as_ref().get_next();
+
+            if !node.as_ref().task.is_valid() {
+                self.list.remove(&node);
+                unsafe { Box::from_raw(*node); }
+            } else if pred(&node.as_ref().data) {
+                self.list.remove(&node);
+                node.as_mut().queued = false;
+                return Some(node.as_ref().task.clone());
+            }
+        }
+        None
+    }
+
+    /// リストのタスクを先頭から順に返すイテレータを返す。
+    #[inline]
+    pub fn iter(&self) -> Iter<T> {
+        Iter {
+            iter: self.list.iter(),
+            _marker: PhantomData
+        }
+    }
+
+    // 終了したタスクのノードが先頭に残らないようにする
+    fn purge_front(&mut self) {
+        while let Some(node) = self.list.front() {
+            if node.as_ref().task.is_valid() {
+                break;
+            }
+            self.list.remove(&node);
+            unsafe { Box::from_raw(*node); }
+        }
+    }
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = &'a Task;
+
+    #[inline]
+    fn next(&mut self) -> Option<&'a Task> {
+        self.iter.next().map(|node| unsafe { &(**node).task })
+    }
+}
@@ -0,0 +1,129 @@
+use sync::wait_list::WaitList;
+use task;
+use time::{Duration, Instant};
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// `wait`メソッドのエラーを表す列挙型。
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum WaitError {
+    /// `WaitQueue`が破棄された。
+    Destroyed,
+    /// 指定した時刻までに起こされなかった。
+    TimedOut
+}
+
+/// `WaitQueue`の`wait`メソッドに特殊化された`Result`。
+pub type WaitResult = Result<(), WaitError>;
+
+/// 条件が満たされるまでタスクを待機させるキュー。
+/// 同期プリミティブの実装に使う。staticに宣言できる。
+pub struct WaitQueue {
+    destroyed: AtomicBool,
+    queue: UnsafeCell<WaitList<()>>
+}
+
+unsafe impl Send for WaitQueue { }
+unsafe impl Sync for WaitQueue { }
+
+/// 現在から`duration`が経過した時刻を返す。
+#[inline]
+pub fn deadline_after(duration: Duration) -> Instant {
+    Instant::now() + duration
+}
+
+impl WaitQueue {
+    /// 待機しているタスクの無いキューを作る。
+    #[inline(always)]
+    pub const fn new() -> WaitQueue {
+        WaitQueue {
+            destroyed: AtomicBool::new(false),
+            queue: UnsafeCell::new(WaitList::new())
+        }
+    }
+
+    /// 待機しているタスクが無ければ`true`を返す。
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        unsafe { (*self.queue.get()).is_empty() }
+    }
+
+    /// 起こされるか`deadline`の時刻になるまでタスクをブロックする。
+    /// `deadline`が`None`の場合は起こされるまで待つ。
+    ///
+    /// キューに加えた後に`release`を呼ぶので、条件を確認してから待機するまでの間に起こされても見逃さない。
+    /// スケジューラのロック(`task::lock`)を保持した状態で呼ばなければならない。
+    pub fn wait<F: FnOnce()>(&self, deadline: Option<Instant>, release: F) -> WaitResult {
+        let this_task = task::this();
+
+        let q = unsafe { &mut *self.queue.get() };
+        let ticket = q.push(this_task.clone(), ());
+        release();
+
+        loop {
+            if !q.is_queued(&ticket) {
+                // キューから取り出された
+                q.remove(ticket);
+                if self.destroyed.load(Ordering::Acquire) {
+                    task::yield_now();// Back to a task which is destroying a queue
+                    return Err(WaitError::Destroyed);
+                }
+                return Ok(());
+            }
+
+            match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if deadline <= now {
+                        q.remove(ticket);
+                        return Err(WaitError::TimedOut);
+                    }
+                    task::sleep(deadline - now);
+                },
+                None => {
+                    let _ = this_task.suspend();
+                }
+            }
+        }
+    }
+
+    /// 待機しているタスクを1つ起こす。
+    /// 起こしたタスクがあれば`true`を返す。
+    pub fn notify_one(&self) -> bool {
+        let _guard = task::lock();
+
+        let q = unsafe { &mut *self.queue.get() };
+        // 終了したタスクは`pop_front`が取り除く
+        // 既に起きているタスクはキューから取り出されたことで起こされたと判断する
+        match q.pop_front() {
+            Some(task) => {
+                let _ = task.resume_later();
+                true
+            },
+            None => false
+        }
+    }
+
+    /// 待機している全てのタスクを起こし、起こしたタスクの数を返す。
+    pub fn notify_all(&self) -> usize {
+        let _guard = task::lock();
+
+        let mut count = 0;
+        while self.notify_one() {
+            count += 1;
+        }
+        count
+    }
+
+    /// キューを破棄する。
+    /// 待機しているタスクは`WaitError::Destroyed`で起こされる。
+    pub unsafe fn destroy(&self) {
+        self.destroyed.store(true, Ordering::Release);
+        let q = &mut *self.queue.get();
+        while let Some(task) = q.pop_front() {
+            let _ = task.resume_later();
+            let _ = task::run_now(&task);
+        }
+        task::yield_now();
+    }
+}
@@ -0,0 +1,991 @@
+use rt::{Force, ForceRef, IntBlocker};
+use arch;
+use arch::interrupt;
+use arch::smp::{self, MAX_CPUS};
+use arch::task::TaskEntity;
+use lists::DList;
+use sync::mutex::MutexLink;
+use sync::spin::{RecursiveSpinLock, RecursiveSpinLockGuard};
+use memory;
+use memory::kcache::{KCacheAllocator, KCBox};
+use timer;
+use time::Duration;
+use core::result;
+use core::cmp;
+use core::mem;
+use core::usize;
+use core::ptr::{self, Shared};
+use core::sync::atomic::{Ordering, AtomicUsize};
+use alloc::boxed::{Box, FnBox};
+
+pub use self::scheduler::{Scheduler, SystemScheduler};
+
+pub mod scheduler;
+pub mod periodic;
+
+/*
+pub const TASK_SWITCH_INTERVAL: usize = ...;
+
+struct TaskEntity {
+    ...
+}
+
+impl TaskEntity {
+    pub fn new() -> TaskEntity {
+        ...
+    }
+
+    pub fn setup(&mut self, entry: extern "C" fn(usize), arg: usize, return_to: fn() -> !) {
+        ...
+    }
+
+    pub fn setup_primary(&mut self) {
+        ...
+    }
+
+    pub fn terminate(&mut self) {
+        ...
+    }
+}
+
+/// Switch to the next task
+pub unsafe fn switch(cur_task: &mut TaskEntity, next_task: &mut TaskEntity) { ... }
+
+/// Switch the task due to it is terminated
+pub unsafe fn leap(next_task: &mut TaskEntity) -> ! { ... }
+
+/// Enter user mode in the current task
+pub unsafe fn enter_user(entry: usize, stack: usize) -> ! { ... }
+*/
+
+#[allow(non_upper_case_globals)]
+static task_counter: AtomicUsize = AtomicUsize::new(1);
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Error {
+    /// タスクが無効。
+    InvalidTask,
+    /// タスクが実行中。
+    InRunning,
+    /// タスクの状態が不正。
+    InvalidState,
+    /// 引数が不正。
+    InvalidArgument,
+    /// 周期タスクの利用率の合計が上限を超える。
+    Overloaded,
+    /// タスクが他のプロセッサに割り当てられている。
+    OtherCpu
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+#[repr(u8)]
+pub enum State {
+    Runnable,
+    Suspended,
+    Free
+}
+
+/// タスクの優先度。値が大きいほど優先される。
+#[derive(PartialEq, Eq, PartialOrd, Ord,Debug, Clone, Copy)]
+pub struct Priority(u8);
+
+/// 優先度の段階数。
+pub const PRIORITY_LEN: usize = 32;
+
+#[allow(non_upper_case_globals)]
+impl Priority {
+    pub const Critical: Priority = Priority((PRIORITY_LEN - 1) as u8);
+    pub const High:     Priority = Priority(24);
+    pub const Middle:   Priority = Priority(16);
+    pub const Low:      Priority = Priority(8);
+    pub const Idle:     Priority = Priority(0);
+
+    /// 数値から優先度を作る。`PRIORITY_LEN`以上の場合は`None`を返す。
+    #[inline]
+    pub fn from_u8(num: u8) -> Option<Priority> {
+        if (num as usize) < PRIORITY_LEN {
+            Some(Priority(num))
+        } else {
+            None
+        }
+    }
+
+    /// 優先度を数値で返す。
+    #[inline(always)]
+    pub fn level(self) -> usize {
+        self.0 as usize
+    }
+}
+
+pub struct TaskData {
+    id: usize,
+    // 領域が再利用されるたびに増える
+    generation: usize,
+    timer: timer::Timer,
+    state: State,
+    // ミューテックスから継承した優先度を含む実際の優先度
+    priority: Priority,
+    // `Task::set_priority`で設定された優先度
+    base_priority: Priority,
+    mutex_link: MutexLink,
+    sched: scheduler::Entity,
+    periodic: Option<periodic::Periodic>,
+    // 割り当てられたプロセッサ。タスクは他のプロセッサに移動しない
+    cpu: usize,
+    // `task_start`から呼ぶ本体とその引数
+    entry: Option<extern "C" fn(usize)>,
+    arg: usize,
+    entity: TaskEntity,
+    prev: Option<Shared<TaskData>>,
+    next: Option<Shared<TaskData>>
+}
+
+impl_linked_node!(Shared<TaskData> { prev: prev, next: next });
+
+impl TaskData {
+    #[inline]
+    fn new() -> TaskData {
+        TaskData {
+            id: usize::MAX,
+            generation: 0,
+            // 配置が決まった後に`bind_timer`でアドレスを渡す
+            timer: timer::Timer::with_context(TaskManager::resume_by_timer, 0),
+            state: State::Free,
+            priority: Task::DEFAULT_PRIORITY,
+            base_priority: Task::DEFAULT_PRIORITY,
+            mutex_link: MutexLink::new(),
+            sched: Default::default(),
+            periodic: None,
+            cpu: 0,
+            entry: None,
+            arg: 0,
+            entity: TaskEntity::new(),
+            prev: None,
+            next: None
+        }
+    }
+
+    #[inline]
+    fn setup(&mut self, id: usize, cpu: usize, entry: extern "C" fn(usize), arg: usize, return_to: fn() -> !) {
+        self.id = id;
+        self.state = State::Runnable;
+        self.priority = Task::DEFAULT_PRIORITY;
+        self.base_priority = Task::DEFAULT_PRIORITY;
+        self.mutex_link = MutexLink::new();
+        self.sched = Default::default();
+        self.periodic = None;
+        self.cpu = cpu;
+        self.entry = Some(entry);
+        self.arg = arg;
+        self.bind_timer();
+        let data = self as *mut TaskData as usize;
+        self.entity.setup(task_start, data, return_to);
+    }
+
+    #[inline]
+    fn setup_primary(&mut self, id: usize, cpu: usize) {
+        self.id = id;
+        self.state = State::Runnable;
+        self.cpu = cpu;
+        self.bind_timer();
+        self.entity.setup_primary();
+    }
+
+    // タイマーの満了時にこのタスクを直接見つけられるようにする
+    #[inline]
+    fn bind_timer(&mut self) {
+        self.timer.set_context(self as *mut TaskData as usize);
+    }
+
+    fn terminate(&mut self) {
+        // 終了したタスクを指す`Task`を無効にする
+        self.generation = self.generation.wrapping_add(1);
+        self.entity.terminate();
+        self.timer.clear();
+    }
+}
+
+#[derive(Clone)]
+pub struct Task {
+    id: usize,
+    generation: usize,
+    ptr: Shared<TaskData>
+}
+
+impl Task {
+    pub const DEFAULT_PRIORITY: Priority = Priority::Middle;
+
+    #[inline(always)]
+    fn new(ptr: Shared<TaskData>) -> Task {
+        unsafe {
+            Task {
+                id: (**ptr).id,
+                generation: (**ptr).generation,
+                ptr: ptr
+            }
+        }
+    }
+
+    #[inline]
+    pub fn this() -> Task {
+        // 読み出している間に他のタスクに切り替わらないようにする
+        let _blocker = IntBlocker::new();
+        manager().cpus[smp::cpu_id()].running_task.clone()
+    }
+
+    #[inline(always)]
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    #[inline(always)]
+    fn data(&self) -> &mut TaskData {
+        unsafe {
+            &mut **self.ptr
+        }
+    }
+
+    #[inline]
+    pub fn is_valid(&self) -> bool {
+        let data = self.data();
+        data.generation == self.generation && data.state != State::Free
+    }
+
+    /// タスクが割り当てられているプロセッサの番号を返す。
+    #[inline]
+    pub fn cpu(&self) -> usize {
+        self.data().cpu
+    }
+
+    #[inline]
+    pub fn priority(&self) -> Result<Priority> {
+        let _guard = lock();
+
+        if !self.is_valid() {
+            return Err(Error::InvalidTask)
+        }
+
+        Ok(self.data().priority)
+    }
+
+    /// ミューテックスから継承した優先度を含まない優先度を返す。
+    #[inline]
+    pub fn base_priority(&self) -> Result<Priority> {
+        let _guard = lock();
+
+        if !self.is_valid() {
+            return Err(Error::InvalidTask)
+        }
+
+        Ok(self.data().base_priority)
+    }
+
+    /// いずれかのプロセッサで実行中ならば`true`を返す。
+    #[inline]
+    pub fn is_running(&self) -> bool {
+        let _guard = lock();
+        manager().is_running(self)
+    }
+
+    #[inline]
+    pub fn is_primary(&self) -> bool {
+        &manager().primary_task == self
+    }
+
+    #[inline]
+    pub fn terminate(&self) -> Result<()> {
+        manager().terminate(self)
+    }
+
+    pub fn set_priority(&self, priority: Priority) -> Result<()> {
+        let _guard = lock();
+
+        if !self.is_valid() {
+            return Err(Error::InvalidTask)
+        }
+
+        self.data().base_priority = priority;
+        if try!(manager().update_priority(self)) {
+            // 待っているミューテックスの所有者にも反映する
+            self.data().mutex_link.priority_changed();
+        }
+
+        Ok(())
+    }
+
+    /// 保持しているミューテックスを待つタスクに合わせて優先度を設定し直す。
+    /// 優先度が変わった場合は`true`を返す。
+    ///
+    /// `sync`モジュールから呼ばれる。
+    #[inline(always)]
+    pub fn update_priority(&self) -> Result<bool> {
+        manager().update_priority(self)
+    }
+
+    /// 優先度の継承のための情報を返す。
+    ///
+    /// `sync`モジュールから呼ばれる。スケジューラのロックを保持した状態で使わなければならない。
+    #[inline(always)]
+    pub fn mutex_link(&self) -> &mut MutexLink {
+        &mut self.data().mutex_link
+    }
+
+    #[inline(always)]
+    pub fn suspend(&self) -> Result<()> {
+        manager().suspend(self)
+    }
+
+    #[inline(always)]
+    pub fn resume(&self) -> Result<()> {
+        manager().resume(self, true)
+    }
+
+    #[inline(always)]
+    pub fn resume_later(&self) -> Result<()> {
+        manager().resume(self, false)
+    }
+}
+
+impl PartialEq for Task {
+    #[inline(always)]
+    fn eq(&self, other: &Task) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Task { }
+
+// プロセッサごとのスケジューラの状態
+struct Cpu {
+    scheduler: SystemScheduler,
+    running_task: Task,
+    task_to_back: Option<Task>,
+    // 割り当てられているタスクの数
+    tasks: usize,
+    // 割り当てられている周期タスクの利用率の合計
+    utilization: u64,
+    // タスク切り替えのタイマー。満了の処理は最初のプロセッサで行われる
+    timer: timer::UnmanagedTimer,
+    // タイマーの処理中に要求された切り替え。全てのタイマーを処理した後に`switch_pending`で行う
+    pending_switch: bool
+}
+
+struct TaskManager {
+    cpus: [Cpu; MAX_CPUS],
+    // `cpus`のうち初期化が済んだ数
+    cpu_count: usize,
+    suspended_tasks: DList<TaskData>,
+    free_tasks: DList<TaskData>,
+    primary_task: Task,
+    kcache: KCacheAllocator<TaskData>
+}
+
+unsafe impl Send for TaskManager { }
+unsafe impl Sync for TaskManager { }
+
+impl TaskManager {
+    #[inline]
+    fn init(&mut self) {
+        unsafe {
+            let _guard = lock();
+
+            let kcache = memory::check_oom_opt(KCacheAllocator::new("Task", mem::align_of::<TaskData>(), None));
+            let mut primary_box = memory::check_oom_opt(KCBox::new(kcache.clone(), TaskData::new()));
+            primary_box.setup_primary(0, 0);
+
+            let primary_task = Task::new(Shared::new(KCBox::into_raw(primary_box)));
+            ptr::write(self, TaskManager {
+                // 各プロセッサの起動時に`add_cpu`で初期化する
+                cpus: mem::uninitialized(),
+                cpu_count: 0,
+                suspended_tasks: DList::new(),
+                free_tasks: DList::new(),
+                primary_task: primary_task.clone(),
+                kcache: kcache
+            });
+            self.add_cpu(0, primary_task);
+
+            // CPU返還タスク
+            let r = self.add_with(yield_task, 0, None, 0).set_priority(Priority::Idle);
+            debug_assert!(r.is_ok());
+        }
+    }
+
+    // 他のプロセッサで実行中の処理をそのプロセッサのアイドルタスクとして登録する
+    fn init_secondary(&mut self) {
+        let _guard = lock();
+
+        unsafe {
+            let cpu = smp::cpu_id();
+            let mut idle_box = memory::check_oom_opt(KCBox::new(self.kcache.clone(), TaskData::new()));
+            idle_box.setup_primary(task_counter.fetch_add(1, Ordering::SeqCst), cpu);
+            idle_box.priority = Priority::Idle;
+            idle_box.base_priority = Priority::Idle;
+
+            let idle_task = Task::new(Shared::new(KCBox::into_raw(idle_box)));
+            self.add_cpu(cpu, idle_task);
+        }
+    }
+
+    // `cpu`番のプロセッサの状態を`task`を実行中として初期化する
+    unsafe fn add_cpu(&mut self, cpu: usize, task: Task) {
+        ptr::write(&mut self.cpus[cpu], Cpu {
+            scheduler: SystemScheduler::new(),
+            running_task: task.clone(),
+            task_to_back: None,
+            tasks: 1,
+            utilization: 0,
+            timer: timer::UnmanagedTimer::with_context(TaskManager::switch_by_timer, cpu),
+            pending_switch: false
+        });
+        self.cpus[cpu].scheduler.push(task.ptr);
+        self.cpu_count = cmp::max(self.cpu_count, cpu + 1);
+
+        self.reset_timer(cpu);
+    }
+
+    // 割り当てられているタスクが最も少ないプロセッサを返す
+    fn least_loaded(&self) -> usize {
+        let mut best = 0;
+        for cpu in 1 .. self.cpu_count {
+            if self.cpus[cpu].tasks < self.cpus[best].tasks {
+                best = cpu;
+            }
+        }
+        best
+    }
+
+    #[inline]
+    fn add(&mut self, entry: extern "C" fn(usize), arg: usize) -> Task {
+        let _guard = lock();
+
+        let cpu = self.least_loaded();
+        self.add_with(entry, arg, None, cpu)
+    }
+
+    fn add_with(&mut self, entry: extern "C" fn(usize), arg: usize, periodic: Option<periodic::Periodic>, cpu: usize) -> Task {
+        let _guard = lock();
+
+        unsafe {
+            let data = self.free_tasks.pop_front().unwrap_or_else(|| {
+                let b = memory::check_oom_opt(KCBox::new(self.kcache.clone(), TaskData::new()));
+                Shared::new(KCBox::into_raw(b))
+            });
+            (**data).setup(task_counter.fetch_add(1, Ordering::SeqCst), cpu, entry, arg, task_terminated);
+            (**data).periodic = periodic;
+            self.cpus[cpu].tasks += 1;
+            self.cpus[cpu].scheduler.push(data);
+
+            if cpu != smp::cpu_id() {
+                // 休止しているかもしれないので起こす
+                smp::reschedule(cpu);
+            }
+
+            Task::new(data)
+        }
+    }
+
+    #[inline]
+    fn is_running(&self, task: &Task) -> bool {
+        &self.cpus[task.data().cpu].running_task == task
+    }
+
+    #[inline]
+    fn reset_timer(&mut self, cpu: usize) {
+        self.cpus[cpu].timer.reset(Duration::from_millis(arch::task::TASK_SWITCH_INTERVAL as u64));
+    }
+
+    #[inline]
+    fn can_switch(&self, cpu: usize) -> bool {
+        let cpu = &self.cpus[cpu];
+        // 他のプロセッサから中断された実行中のタスクはスケジューラから取り除かれている
+        cpu.task_to_back.is_some()
+            || cpu.running_task.data().state != State::Runnable
+            || cpu.scheduler.can_switch(cpu.running_task.ptr)
+    }
+
+    fn switch_by_timer(cpu: usize) {
+        if cpu == smp::cpu_id() {
+            let _guard = lock();
+            manager().cpus[cpu].pending_switch = true;
+        } else {
+            // タイマーは最初のプロセッサで満了するので、割り当てられたプロセッサに切り替えを依頼する
+            smp::reschedule(cpu);
+        }
+    }
+
+    // タイマーの処理中に要求された切り替えを行う
+    fn switch_pending(&mut self) {
+        let _guard = lock();
+
+        let cpu = smp::cpu_id();
+        if mem::replace(&mut self.cpus[cpu].pending_switch, false) {
+            self.reschedule();
+        }
+    }
+
+    // 切り替えるべきタスクがあれば切り替え、無ければ実行中のタスクの時間を延ばす
+    fn reschedule(&mut self) {
+        let _guard = lock();
+
+        let cpu = smp::cpu_id();
+        if self.can_switch(cpu) {
+            self.switch_to_next();
+        } else {
+            self.reset_timer(cpu);
+        }
+    }
+
+    #[inline]
+    fn preempt(&mut self) {
+        let _guard = lock();
+
+        if self.can_switch(smp::cpu_id()) {
+            self.switch_to_next();
+        }
+    }
+
+    fn idle(&mut self) {
+        let _guard = lock();
+
+        let cpu = smp::cpu_id();
+        if self.can_switch(cpu) {
+            self.switch_to_next();
+            return;
+        }
+
+        // 他に実行するタスクが無いので、タスク切り替えのタイマーを止めて休止する
+        // 休止している間は他のプロセッサがタスクを操作できるようにロックを外しておく
+        self.cpus[cpu].timer.clear();
+        unsafe {
+            let depth = SCHED_LOCK.release_all();
+            timer::idle();
+            SCHED_LOCK.reacquire(depth);
+        }
+    }
+
+    fn yield_now(&mut self) {
+        let _guard = lock();
+
+        if self.can_switch(smp::cpu_id()) {
+            self.switch_to_next();
+        } else {
+            unsafe {
+                let depth = SCHED_LOCK.release_all();
+                interrupt::enable_wait();
+                interrupt::disable();
+                SCHED_LOCK.reacquire(depth);
+            }
+        }
+    }
+
+    // 内部変数を次のタスクに移行し、次のタスクを返す
+    fn forward_task(&mut self, cpu: usize) -> Task {
+        let cpu = &mut self.cpus[cpu];
+        let next = if let Some(next) = cpu.task_to_back.take() {
+            next
+        } else {
+            Task::new(cpu.scheduler.next(Some(cpu.running_task.ptr)))
+        };
+
+        cpu.running_task = next;
+
+        cpu.running_task.clone()
+    }
+
+    // スケジューラのロックを保持したまま切り替える
+    // ロックの深さはタスクごとに異なるので、戻ってきた時に切り替え前の深さに戻す
+    // 休止中の割り込みから切り替える場合に備えて、止めていたティックを再開しておく
+    fn switch(cur_task: &Task, next_task: &Task) {
+        timer::resume_tick();
+        unsafe {
+            let depth = SCHED_LOCK.depth();
+            arch::task::switch(&mut cur_task.data().entity, &mut next_task.data().entity);
+            SCHED_LOCK.set_depth(depth);
+        }
+    }
+
+    fn switch_to_next(&mut self) {
+        let cpu = smp::cpu_id();
+        self.reset_timer(cpu);
+
+        let cur_task = self.cpus[cpu].running_task.clone();
+        let next_task = self.forward_task(cpu);
+        if cur_task != next_task {
+            TaskManager::switch(&cur_task, &next_task);
+        }
+    }
+
+    fn run_now(&mut self, next_task: &Task) -> Result<()> {
+        let _guard = lock();
+
+        if !next_task.is_valid() {
+            return Err(Error::InvalidTask);
+        }
+        if self.is_running(next_task) {
+            return Err(Error::InRunning);
+        }
+        let cpu = smp::cpu_id();
+        if next_task.data().cpu != cpu {
+            return Err(Error::OtherCpu);
+        }
+
+        self.reset_timer(cpu);
+
+        let cur_task = self.cpus[cpu].running_task.clone();
+        self.cpus[cpu].task_to_back = Some(cur_task.clone());
+        self.cpus[cpu].running_task = next_task.clone();
+        TaskManager::switch(&cur_task, next_task);
+
+        Ok(())
+    }
+
+    fn terminate_task(&mut self, task: &Task) -> Result<()> {
+        let data = task.data();
+
+        match data.state {
+            State::Runnable => self.cpus[data.cpu].scheduler.remove(task.ptr),
+            State::Suspended => self.suspended_tasks.remove(&task.ptr),
+            State::Free => return Err(Error::InvalidState)
+        }
+
+        if let Some(periodic) = data.periodic.take() {
+            self.cpus[data.cpu].utilization -= periodic.utilization();
+        }
+        self.cpus[data.cpu].tasks -= 1;
+
+        // ここでは解放しない
+        data.state = State::Free;
+        self.free_tasks.push_back(task.ptr);
+
+        data.terminate();
+
+        Ok(())
+    }
+
+    fn terminate(&mut self, task: &Task) -> Result<()> {
+        let _guard = lock();
+
+        if !task.is_valid() {
+            return Err(Error::InvalidTask)
+        }
+        if self.is_running(task) {
+            return Err(Error::InRunning)
+        }
+
+        let r = self.terminate_task(task);
+        debug_assert!(r.is_ok());
+
+        Ok(())
+    }
+
+    fn terminated(&mut self) -> ! {
+        debug_log!("Terminating task {}", Task::this().id());
+
+        // 戻ってこないので、ロックは切り替え先のタスクが解除する
+        unsafe {
+            interrupt::disable();
+            SCHED_LOCK.acquire();
+        }
+
+        let cpu = smp::cpu_id();
+        let cur_task = self.cpus[cpu].running_task.clone();
+        let r = self.terminate_task(&cur_task);
+        debug_assert!(r.is_ok());
+
+        let next_task = self.forward_task(cpu);
+        debug_assert!(cur_task != next_task);
+
+        self.reset_timer(cpu);
+        unsafe {
+            arch::task::leap(&mut next_task.data().entity);
+        }
+    }
+
+    fn set_priority(&mut self, task: &Task, priority: Priority) -> Result<()> {
+        let _guard = lock();
+
+        if !task.is_valid() {
+            return Err(Error::InvalidTask)
+        }
+
+        let data = task.data();
+        match data.state {
+            State::Runnable => if data.priority != priority {
+                let scheduler = &mut self.cpus[data.cpu].scheduler;
+                scheduler.remove(task.ptr);
+                data.priority = priority;
+                scheduler.push(task.ptr);
+            },
+            State::Suspended => data.priority = priority,
+            State::Free => return Err(Error::InvalidState)
+        }
+
+        Ok(())
+    }
+
+    // 設定された優先度と継承した優先度のうち高い方を実際の優先度とする
+    fn update_priority(&mut self, task: &Task) -> Result<bool> {
+        let _guard = lock();
+
+        if !task.is_valid() {
+            return Err(Error::InvalidTask)
+        }
+
+        let data = task.data();
+        let priority = match data.mutex_link.inherited_priority() {
+            Some(inherited) => cmp::max(data.base_priority, inherited),
+            None => data.base_priority
+        };
+        if data.priority == priority {
+            return Ok(false);
+        }
+
+        try!(self.set_priority(task, priority));
+        Ok(true)
+    }
+
+    fn resume_by_timer(context: usize) {
+        let _guard = lock();
+
+        unsafe {
+            let data = Shared::new(context as *mut TaskData);
+            // sleep中にresumeされる場合がある
+            if (**data).state == State::Suspended {
+                let mut man = manager();
+                let r = man.resume(&Task::new(data), false);
+                debug_assert!(r.is_ok());
+
+                // 周期タスクの起動はデッドラインに間に合うよう、タイマーの処理が済み次第切り替える
+                let cpu = (**data).cpu;
+                if (**data).periodic.is_some() && cpu == smp::cpu_id() {
+                    man.cpus[cpu].pending_switch = true;
+                }
+            }
+        }
+    }
+
+    fn suspend(&mut self, task: &Task) -> Result<()> {
+        let _guard = lock();
+
+        if !task.is_valid() {
+            return Err(Error::InvalidTask)
+        }
+
+        let data = task.data();
+        if data.state != State::Runnable {
+            return Err(Error::InvalidState)
+        }
+        data.state = State::Suspended;
+
+        self.cpus[data.cpu].scheduler.remove(task.ptr);
+        self.suspended_tasks.push_back(task.ptr);
+
+        if self.is_running(task) {
+            if data.cpu == smp::cpu_id() {
+                self.switch_to_next();
+            } else {
+                smp::reschedule(data.cpu);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn resume(&mut self, task: &Task, now: bool) -> Result<()> {
+        let _guard = lock();
+
+        if !task.is_valid() {
+            return Err(Error::InvalidTask)
+        }
+
+        let data = task.data();
+        if data.state != State::Suspended {
+            return Err(Error::InvalidState)
+        }
+        data.state = State::Runnable;
+        // タイムアウトを待たずに起こされた場合は取り消す
+        data.timer.clear();
+
+        self.suspended_tasks.remove(&task.ptr);
+        self.cpus[data.cpu].scheduler.push(task.ptr);
+
+        if data.cpu != smp::cpu_id() {
+            // 切り替えるかどうかは割り当てられたプロセッサで判断する
+            smp::reschedule(data.cpu);
+        } else if now {
+            self.switch_to_next();
+        }
+
+        Ok(())
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        let _guard = lock();
+
+        let task = Task::this();
+        task.data().timer.reset(duration);
+        assert!(task.suspend().is_ok());
+    }
+}
+
+// 全てのタスクはここから始まる
+// 切り替え元が保持していたスケジューラのロックを解除してから本体を呼ぶ
+extern "C" fn task_start(data: usize) {
+    let (entry, arg) = unsafe {
+        interrupt::disable();
+        SCHED_LOCK.release_all();
+        interrupt::enable();
+
+        let data = &*(data as *const TaskData);
+        (data.entry.unwrap(), data.arg)
+    };
+    entry(arg);
+}
+
+fn task_terminated() -> ! {
+    manager().terminated();
+}
+
+extern "C" fn yield_task(_: usize) {
+    loop {
+        manager().idle();
+    }
+}
+
+// 全てのプロセッサのスケジューラと`sync`の待機キューを1つのロックで保護する
+//
+// 待機キューへの登録から休止までを、他のプロセッサからの`resume`に対して不可分にする必要がある。
+// また優先度の継承は他のプロセッサに割り当てられたタスクの優先度も書き換え、
+// `suspend`や`resume`も他のプロセッサのスケジューラを操作するため、
+// プロセッサごとにロックを分けると取得順序が操作ごとに変わってしまう。
+// 保持するのはリストの操作の間だけで、タスクの本体を実行している間は解除されているので、
+// 扱うプロセッサの数(`MAX_CPUS`)が少ない間は並列性よりこの単純さを優先する。
+static SCHED_LOCK: RecursiveSpinLock = RecursiveSpinLock::new();
+
+static MANAGER: Force<TaskManager> = Force::new();
+
+#[inline]
+pub fn init() {
+    MANAGER.setup().init();
+}
+
+/// 他のプロセッサの起動時に呼び、実行中の処理をそのプロセッサのアイドルタスクとして登録する。
+#[inline]
+pub fn init_secondary() {
+    manager().init_secondary();
+}
+
+#[inline(always)]
+fn manager() -> ForceRef<TaskManager> {
+    MANAGER.as_ref()
+}
+
+/// スケジューラのロックを取得する。
+///
+/// タスクの状態と`sync`モジュールの待機キューはこのロックで保護される。
+/// 同じプロセッサからは重ねて取得でき、保持したままタスクを切り替えられる。
+///
+/// 取得の順序はこのロック、イベントのキュー、タイマー、メモリの順とする。
+/// 後のロックを保持したままこのロックを取得してはならない。
+#[inline]
+pub fn lock() -> RecursiveSpinLockGuard<'static> {
+    SCHED_LOCK.lock()
+}
+
+#[inline(always)]
+pub fn add(entry: extern "C" fn(usize), arg: usize) -> Task {
+    manager().add(entry, arg)
+}
+
+pub fn spawn<F: FnOnce() + Send + 'static>(f: F) -> Task
+{
+    let _guard = lock();
+
+    let main = move || {
+        // TODO: catch exceptions
+        f();
+    };
+
+    let mut man = manager();
+    let p: Box<FnBox()> = Box::new(main);
+    let task = man.add(spawn_entry, Box::into_raw(Box::new(p)) as usize);
+
+    // Switch to the spawning task immediately
+    // 他のプロセッサに割り当てられた場合はそちらで実行される
+    let _ = man.run_now(&task);
+
+    task
+}
+
+/// ユーザーモードで`entry`から実行するタスクを作る。`stack`はユーザーモードで使うスタックの末尾のアドレス。
+///
+/// `entry`と`stack`の領域は、カーネルとは別のページとしてユーザー空間に対応付けておく必要がある。
+/// ユーザーモードで例外が起きた場合は、カーネルを止めずにそのタスクだけを終了する。
+pub fn spawn_user(entry: usize, stack: usize) -> Task {
+    spawn(move || unsafe {
+        arch::task::enter_user(entry, stack);
+    })
+}
+
+extern "C" fn spawn_entry(main: usize) {
+    let main = main as *mut Box<FnBox()>;
+    unsafe {
+        Box::from_raw(main)();
+    }
+}
+
+// TODO: try
+
+#[inline(always)]
+pub fn this() -> Task {
+    Task::this()
+}
+
+#[inline(always)]
+pub fn exit() -> ! {
+    manager().terminated();
+}
+
+#[inline(always)]
+pub fn sleep(duration: Duration) {
+    manager().sleep(duration);
+}
+
+#[inline(always)]
+pub fn yield_now() {
+    manager().yield_now();
+}
+
+#[inline(always)]
+pub fn run_now(task: &Task) -> Result<()> {
+    manager().run_now(task)
+}
+
+/// 実行中のプロセッサでタスクを切り替えるべきか判断し、必要ならば切り替える。
+/// 他のプロセッサからの要求を受けた割り込みハンドラから呼ばれる。
+#[inline(always)]
+pub fn reschedule() {
+    manager().reschedule();
+}
+
+/// タイマーの処理中に要求されたタスクの切り替えを行う。
+/// 満了したタイマーを全て処理した後に`timer`から呼ばれる。
+#[inline]
+pub fn switch_pending() {
+    // タスクの管理を始める前からタイマーは動いている
+    if MANAGER.can_use() {
+        manager().switch_pending();
+    }
+}
+
+/// 実行するタスクが無い間、プロセッサを休止させ続ける。
+/// `init_secondary`を呼んだ後に呼ぶ。
+pub fn idle() -> ! {
+    loop {
+        manager().idle();
+    }
+}
+
@@ -0,0 +1,233 @@
+use super::{manager, lock, spawn_entry, Task, Error, Result};
+use time::{Duration, Instant};
+use core::cmp;
+use core::sync::atomic::{Ordering, AtomicUsize};
+use alloc::boxed::{Box, FnBox};
+
+/// 利用率の固定小数点表現における1.0。
+pub const UTILIZATION_SCALE: u64 = 1 << 20;
+
+#[allow(non_upper_case_globals)]
+static total_misses: AtomicUsize = AtomicUsize::new(0);
+
+/// 周期タスクの実行条件。
+#[derive(Clone, Copy, Debug)]
+pub struct Params {
+    /// 起動周期。
+    pub period: Duration,
+    /// 起動時刻からの相対デッドライン。`period`以下でなければならない。
+    pub deadline: Duration,
+    /// 1回の起動あたりの最悪実行時間。`deadline`以下でなければならない。
+    pub cost: Duration
+}
+
+/// 周期タスクの統計情報。
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    /// 起動された回数。
+    pub releases: usize,
+    /// 処理を完了した回数。
+    pub completions: usize,
+    /// デッドラインに間に合わなかった回数。
+    pub deadline_misses: usize,
+    /// 起動から完了までにかかった最大の時間。
+    pub max_response: Duration
+}
+
+/// タスクごとに保持する周期タスクの状態。
+pub struct Periodic {
+    params: Params,
+    release: Instant,
+    deadline: Instant,
+    stats: Stats
+}
+
+impl Params {
+    /// デッドラインを周期と同じとした実行条件を作る。
+    #[inline]
+    pub const fn new(period: Duration, cost: Duration) -> Params {
+        Params {
+            period: period,
+            deadline: period,
+            cost: cost
+        }
+    }
+
+    /// デッドラインを指定して実行条件を作る。
+    #[inline]
+    pub const fn with_deadline(period: Duration, deadline: Duration, cost: Duration) -> Params {
+        Params {
+            period: period,
+            deadline: deadline,
+            cost: cost
+        }
+    }
+
+    #[inline]
+    fn is_valid(&self) -> bool {
+        !self.cost.is_zero() && self.cost <= self.deadline && self.deadline <= self.period
+    }
+
+    // 利用率(cost / period)を切り上げて返す。
+    #[inline]
+    fn utilization(&self) -> u64 {
+        let period = self.period.as_nanos();
+        (self.cost.as_nanos() * UTILIZATION_SCALE + period - 1) / period
+    }
+}
+
+impl Periodic {
+    #[inline]
+    fn new(params: Params, now: Instant) -> Periodic {
+        Periodic {
+            params: params,
+            release: now,
+            deadline: now + params.deadline,
+            stats: Stats {
+                releases: 1,
+                .. Stats::default()
+            }
+        }
+    }
+
+    /// 現在の起動に対する絶対デッドラインを返す。
+    #[inline(always)]
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    // 処理の完了を記録して次の起動時刻に進め、それまでの時間を返す
+    fn complete(&mut self, now: Instant) -> Duration {
+        self.stats.completions += 1;
+        self.stats.max_response = cmp::max(self.stats.max_response, now - self.release);
+        if now > self.deadline {
+            self.miss();
+        }
+
+        self.release += self.params.period;
+        self.stats.releases += 1;
+
+        // 既にデッドラインを過ぎた起動は実行せずにデッドラインミスとする
+        while self.release + self.params.deadline <= now {
+            self.miss();
+            self.release += self.params.period;
+            self.stats.releases += 1;
+        }
+        self.deadline = self.release + self.params.deadline;
+
+        // 既に起動時刻を過ぎていれば0になる
+        self.release - now
+    }
+
+    #[inline]
+    fn miss(&mut self) {
+        self.stats.deadline_misses += 1;
+        total_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn utilization(&self) -> u64 {
+        self.params.utilization()
+    }
+}
+
+impl Task {
+    /// 周期タスクの統計情報を返す。
+    pub fn periodic_stats(&self) -> Result<Stats> {
+        let _guard = lock();
+
+        if !self.is_valid() {
+            return Err(Error::InvalidTask);
+        }
+
+        match self.data().periodic {
+            Some(ref periodic) => Ok(periodic.stats),
+            None => Err(Error::InvalidState)
+        }
+    }
+}
+
+/// `f`を`params`で指定した周期で繰り返し実行するタスクを作る。
+///
+/// 周期タスクはデッドラインの早い順に、他のタスクより優先して実行される。
+/// 利用率(`cost / period`)の合計が1を超えないプロセッサのうち、最も空いているものに割り当てられる。
+/// どのプロセッサにも収まらない場合は`Error::Overloaded`を返す。
+///
+/// デッドラインが周期と同じならば、受け入れた周期タスクはデッドラインに間に合う。
+/// デッドラインが周期より短い場合はこの条件だけでは保証されず、間に合わなければデッドラインミスとして数えられる。
+pub fn spawn<F: FnMut() + Send + 'static>(params: Params, mut f: F) -> Result<Task> {
+    if !params.is_valid() {
+        return Err(Error::InvalidArgument);
+    }
+
+    let _guard = lock();
+
+    // 受け入れ制御
+    let mut man = manager();
+    let required = params.utilization();
+    let mut cpu: Option<usize> = None;
+    for i in 0 .. man.cpu_count {
+        let utilization = man.cpus[i].utilization;
+        if utilization + required <= UTILIZATION_SCALE
+            && cpu.map_or(true, |cpu| utilization < man.cpus[cpu].utilization) {
+            cpu = Some(i);
+        }
+    }
+    let cpu = match cpu {
+        Some(cpu) => cpu,
+        None => return Err(Error::Overloaded)
+    };
+    man.cpus[cpu].utilization += required;
+
+    let main = move || {
+        loop {
+            f();
+            wait_next_period();
+        }
+    };
+
+    let p: Box<FnBox()> = Box::new(main);
+    let periodic = Periodic::new(params, Instant::now());
+    let task = man.add_with(spawn_entry, Box::into_raw(Box::new(p)) as usize, Some(periodic), cpu);
+
+    // デッドラインが早ければすぐに実行する
+    man.preempt();
+
+    Ok(task)
+}
+
+// 今回の処理を完了し、次の起動まで待機する
+fn wait_next_period() {
+    let _guard = lock();
+
+    let mut man = manager();
+    let task = Task::this();
+    let cpu = task.data().cpu;
+
+    // デッドラインが変わるので並べ直す
+    man.cpus[cpu].scheduler.remove(task.ptr);
+    let delay = task.data().periodic.as_mut().unwrap().complete(Instant::now());
+    man.cpus[cpu].scheduler.push(task.ptr);
+
+    if delay.is_zero() {
+        man.preempt();
+    } else {
+        task.data().timer.reset(delay);
+        let r = task.suspend();
+        debug_assert!(r.is_ok());
+    }
+}
+
+/// 全てのプロセッサの周期タスクの利用率の合計を`UTILIZATION_SCALE`を1.0とした固定小数点で返す。
+#[inline]
+pub fn utilization() -> u64 {
+    let _guard = lock();
+    let man = manager();
+    (0 .. man.cpu_count).fold(0, |sum, cpu| sum + man.cpus[cpu].utilization)
+}
+
+/// 全ての周期タスクのデッドラインミスの合計を返す。
+#[inline]
+pub fn deadline_misses() -> usize {
+    total_misses.load(Ordering::Relaxed)
+}
@@ -0,0 +1,65 @@
+use super::Scheduler;
+use super::super::TaskData;
+use lists::SortedList;
+use core::cmp::Ordering;
+use core::ptr::Shared;
+
+/// 周期タスクをデッドラインの早い順に実行するスケジューラ。
+///
+/// 周期タスク以外のタスクは`S`に委ね、実行可能な周期タスクが無い時だけ実行される。
+pub struct Edf<S: Scheduler> {
+    realtime_tasks: SortedList<TaskData>,
+    inner: S
+}
+
+#[inline]
+fn is_realtime(task: Shared<TaskData>) -> bool {
+    unsafe { (**task).periodic.is_some() }
+}
+
+fn cmp_deadline(a: &TaskData, b: &TaskData) -> Ordering {
+    let a = a.periodic.as_ref().unwrap().deadline();
+    let b = b.periodic.as_ref().unwrap().deadline();
+    a.cmp(&b)
+}
+
+impl<S: Scheduler> Scheduler for Edf<S> {
+    type Entity = S::Entity;
+
+    fn new() -> Edf<S> {
+        Edf {
+            realtime_tasks: SortedList::new(cmp_deadline),
+            inner: S::new()
+        }
+    }
+
+    fn push(&mut self, task: Shared<TaskData>) {
+        if is_realtime(task) {
+            self.realtime_tasks.push(task);
+        } else {
+            self.inner.push(task);
+        }
+    }
+
+    fn remove(&mut self, task: Shared<TaskData>) {
+        if is_realtime(task) {
+            self.realtime_tasks.remove(&task);
+        } else {
+            self.inner.remove(task);
+        }
+    }
+
+    fn can_switch(&self, running: Shared<TaskData>) -> bool {
+        match self.realtime_tasks.iter().next() {
+            Some(task) => *task != *running,
+            None => self.inner.can_switch(running)
+        }
+    }
+
+    fn next(&mut self, running: Option<Shared<TaskData>>) -> Shared<TaskData> {
+        match self.realtime_tasks.front() {
+            Some(task) => task,
+            None => self.inner.next(running.and_then(|task| if is_realtime(task) { None } else { Some(task) }))
+        }
+    }
+}
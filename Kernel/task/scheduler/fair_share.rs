@@ -0,0 +1,119 @@
+use super::Scheduler;
+use super::super::{TaskData, State, Priority};
+use lists::{DList, SortedList};
+use timer;
+use core::cmp::{self, Ordering};
+use core::ptr::Shared;
+
+// 重み1のタスクが1ミリ秒実行された時に進む仮想実行時間
+const WEIGHT_SCALE: u64 = 1024;
+
+/// 優先度に応じた重みでCPU時間を分配するスケジューラ。
+///
+/// 仮想実行時間の最も短いタスクを実行する。
+/// `Priority::Idle`のタスクは他に実行可能なタスクが無い時だけ実行される。
+pub struct FairShare {
+    runnable_tasks: SortedList<TaskData>,
+    idle_tasks: DList<TaskData>,
+    min_vruntime: u64,
+    last_switch: u64
+}
+
+/// `FairShare`がタスクごとに保持する情報。
+#[derive(Default)]
+pub struct Entity {
+    vruntime: u64
+}
+
+// 優先度が4段階上がるごとに重みを2倍にする
+#[inline]
+fn weight(priority: Priority) -> u64 {
+    1 << (priority.level() / 4)
+}
+
+impl FairShare {
+    fn cmp(a: &TaskData, b: &TaskData) -> Ordering {
+        a.sched.vruntime.cmp(&b.sched.vruntime)
+    }
+
+    // 前回の切り替えからの経過時間を実行中のタスクに加算する
+    fn charge(&mut self, running: Option<Shared<TaskData>>) {
+        let now = timer::manager().counter();
+        let elapsed = now.wrapping_sub(self.last_switch);
+        self.last_switch = now;
+
+        let running = match running {
+            Some(running) => running,
+            None => return
+        };
+        let data = unsafe { &mut **running };
+        if data.priority == Priority::Idle {
+            return;
+        }
+
+        data.sched.vruntime += elapsed * WEIGHT_SCALE / weight(data.priority);
+        if data.state == State::Runnable {
+            // 仮想実行時間の順に並べ直す
+            self.runnable_tasks.remove(&running);
+            self.runnable_tasks.push(running);
+        }
+
+        if let Some(front) = self.runnable_tasks.front() {
+            self.min_vruntime = cmp::max(self.min_vruntime, unsafe { (**front).sched.vruntime });
+        }
+    }
+}
+
+impl Scheduler for FairShare {
+    type Entity = Entity;
+
+    fn new() -> FairShare {
+        FairShare {
+            runnable_tasks: SortedList::new(FairShare::cmp),
+            idle_tasks: DList::new(),
+            min_vruntime: 0,
+            last_switch: 0
+        }
+    }
+
+    fn push(&mut self, task: Shared<TaskData>) {
+        let data = unsafe { &mut **task };
+        if data.priority == Priority::Idle {
+            self.idle_tasks.push_back(task);
+        } else {
+            // 長い間休止していたタスクがCPUを独占しないようにする
+            data.sched.vruntime = cmp::max(data.sched.vruntime, self.min_vruntime);
+            self.runnable_tasks.push(task);
+        }
+    }
+
+    fn remove(&mut self, task: Shared<TaskData>) {
+        if unsafe { (**task).priority } == Priority::Idle {
+            self.idle_tasks.remove(&task);
+        } else {
+            self.runnable_tasks.remove(&task);
+        }
+    }
+
+    fn can_switch(&self, running: Shared<TaskData>) -> bool {
+        if unsafe { (**running).priority } == Priority::Idle {
+            !self.runnable_tasks.is_empty() || self.idle_tasks.len() > 1
+        } else {
+            self.runnable_tasks.len() > 1
+        }
+    }
+
+    fn next(&mut self, running: Option<Shared<TaskData>>) -> Shared<TaskData> {
+        self.charge(running);
+
+        match self.runnable_tasks.front() {
+            Some(task) => task,
+            None => {
+                // アイドルタスクはラウンドロビン
+                let task = self.idle_tasks.pop_front().unwrap();
+                self.idle_tasks.push_back(task);
+                task
+            }
+        }
+    }
+}
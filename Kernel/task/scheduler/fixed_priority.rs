@@ -0,0 +1,110 @@
+use super::Scheduler;
+use super::super::{Task, TaskData, State, Priority, PRIORITY_LEN};
+use lists::{LinkedNode, DList};
+use core::mem;
+use core::ptr::{self, Shared};
+
+// 実行可能状態のタスクがある優先度を表すビットマップの1語
+type ReadyWord = u32;
+const WORD_BITS: usize = 32;
+// `PRIORITY_LEN`を表すのに必要な語数
+const READY_WORDS: usize = (PRIORITY_LEN + WORD_BITS - 1) / WORD_BITS;
+
+/// 最も高い優先度のタスクをラウンドロビンで実行するスケジューラ。
+pub struct FixedPriority {
+    runnable_tasks: [DList<TaskData>; PRIORITY_LEN],
+    // 実行可能状態タスクのある優先度のビットが立つ
+    ready: [ReadyWord; READY_WORDS],
+    next_priority: Priority
+}
+
+impl FixedPriority {
+    // 実行可能状態タスクのある最も高い優先度を返す。
+    // 上位の語から見るので、`PRIORITY_LEN`が語の幅以下ならば1回の検索で済む
+    #[inline]
+    fn highest_priority(&self) -> Priority {
+        for (i, &word) in self.ready.iter().enumerate().rev() {
+            if word != 0 {
+                let bit = WORD_BITS - 1 - word.leading_zeros() as usize;
+                return Priority((i * WORD_BITS + bit) as u8);
+            }
+        }
+        unreachable!()
+    }
+
+    #[inline]
+    fn set_ready(&mut self, level: usize) {
+        self.ready[level / WORD_BITS] |= 1 << (level % WORD_BITS);
+    }
+
+    #[inline]
+    fn clear_ready(&mut self, level: usize) {
+        self.ready[level / WORD_BITS] &= !(1 << (level % WORD_BITS));
+    }
+}
+
+impl Scheduler for FixedPriority {
+    type Entity = ();
+
+    fn new() -> FixedPriority {
+        unsafe {
+            let mut sched = FixedPriority {
+                runnable_tasks: mem::uninitialized(),
+                ready: [0; READY_WORDS],
+                next_priority: Task::DEFAULT_PRIORITY
+            };
+
+            for list in sched.runnable_tasks.iter_mut() {
+                ptr::write(list, DList::new());
+            }
+
+            sched
+        }
+    }
+
+    fn push(&mut self, task: Shared<TaskData>) {
+        let priority = unsafe { (**task).priority };
+        self.runnable_tasks[priority.level()].push_back(task);
+        self.set_ready(priority.level());
+
+        if priority > self.next_priority {
+            self.next_priority = priority;
+        }
+    }
+
+    fn remove(&mut self, task: Shared<TaskData>) {
+        let priority = unsafe { (**task).priority };
+        let empty = {
+            let tasks = &mut self.runnable_tasks[priority.level()];
+            tasks.remove(&task);
+            tasks.is_empty()
+        };
+        if empty {
+            self.clear_ready(priority.level());
+        }
+
+        self.next_priority = self.highest_priority();
+    }
+
+    #[inline]
+    fn can_switch(&self, running: Shared<TaskData>) -> bool {
+        let priority = unsafe { (**running).priority };
+        self.runnable_tasks[priority.level()].len() != 1 || priority != self.next_priority
+    }
+
+    fn next(&mut self, running: Option<Shared<TaskData>>) -> Shared<TaskData> {
+        // 休止したタスクのリンクは休止中のタスクのリストを指しているので辿らない
+        match running {
+            Some(running) if unsafe {
+                (**running).state == State::Runnable && (**running).priority == self.next_priority
+            } => {
+                // 次のタスクか最初のタスク
+                let tasks = &self.runnable_tasks[self.next_priority.level()];
+                unsafe {
+                    LinkedNode::get_next(&**running).unwrap_or_else(|| tasks.front().unwrap())
+                }
+            },
+            _ => self.runnable_tasks[self.next_priority.level()].front().unwrap()
+        }
+    }
+}
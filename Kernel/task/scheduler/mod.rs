@@ -0,0 +1,55 @@
+pub use self::fixed_priority::FixedPriority;
+#[cfg(sched="fair_share")]
+pub use self::fair_share::FairShare;
+pub use self::edf::Edf;
+
+use super::TaskData;
+use core::ptr::Shared;
+
+pub mod fixed_priority;
+// タスクごとの情報を`TaskData`に持つため、選択された時のみビルドする
+#[cfg(sched="fair_share")]
+pub mod fair_share;
+pub mod edf;
+
+/// 実行可能状態のタスクを保持し、次に実行するタスクを決定するトレイト。
+///
+/// 実行中のタスクも実行可能状態のタスクとして保持される。
+/// スケジューラはプロセッサごとに作られ、そのプロセッサに割り当てられたタスクのみを扱う。
+/// 全てのメソッドはスケジューラのロックを保持した状態で呼ばれる。
+pub trait Scheduler {
+    /// スケジューラがタスクごとに保持する情報。
+    type Entity: Default;
+
+    /// 空のスケジューラを作る。
+    fn new() -> Self;
+
+    /// タスクを実行可能状態のタスクに加える。
+    fn push(&mut self, task: Shared<TaskData>);
+
+    /// タスクを実行可能状態のタスクから取り除く。
+    fn remove(&mut self, task: Shared<TaskData>);
+
+    /// 実行中のタスクを他のタスクに切り替えるべきならば`true`を返す。
+    fn can_switch(&self, running: Shared<TaskData>) -> bool;
+
+    /// 次に実行するタスクを返す。
+    ///
+    /// `running`は既に実行可能状態のタスクから取り除かれている場合がある。
+    /// その場合、`running`のリンクは休止中のタスクなど他のリストに使われているので辿ってはならない。
+    /// 実行中のタスクがこのスケジューラの管理下に無い場合、`running`は`None`となる。
+    /// 実行中のタスクを引き続き実行する場合は`running`を返してもよい。
+    fn next(&mut self, running: Option<Shared<TaskData>>) -> Shared<TaskData>;
+}
+
+/// ビルド時に選択されたスケジューラ。
+///
+/// `--cfg sched="..."`で選択する。指定が無い場合は`FixedPriority`となる。
+/// 周期タスクは選択に関わらず`Edf`によって優先的に実行される。
+#[cfg(not(sched="fair_share"))]
+pub type SystemScheduler = Edf<FixedPriority>;
+#[cfg(sched="fair_share")]
+pub type SystemScheduler = Edf<FairShare>;
+
+/// `SystemScheduler`がタスクごとに保持する情報。
+pub type Entity = <SystemScheduler as Scheduler>::Entity;
@@ -0,0 +1,449 @@
+use arch::interrupt;
+use task;
+use core::fmt;
+use core::cmp::Ordering;
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+use core::u64;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const NANOS_PER_MILLI: u64 = 1_000_000;
+const NANOS_PER_MICRO: u64 = 1_000;
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// UNIX時刻の起点(1970-01-01 00:00:00)。
+pub const UNIX_EPOCH: SystemTime = SystemTime(Duration::ZERO);
+
+// 起動時のUNIX時刻
+#[allow(non_upper_case_globals)]
+static mut boot_time: Duration = Duration::ZERO;
+
+/// 時間の長さ。ナノ秒単位で保持する。
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Default, Hash)]
+pub struct Duration(u64);
+
+/// 起動時からの単調増加する時刻。ナノ秒単位で保持する。
+///
+/// x86ではTSC、BCMではシステムタイマーを使い、タイマーの割り込み間隔より細かい分解能を持つ。
+/// 比較は差の符号で行うため、値が一周しても前後関係を正しく判定できる。
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Hash)]
+pub struct Instant(u64);
+
+impl Duration {
+    pub const ZERO: Duration = Duration(0);
+    pub const MAX: Duration = Duration(u64::MAX);
+
+    #[inline]
+    pub const fn from_secs(secs: u64) -> Duration {
+        Duration(secs * NANOS_PER_SEC)
+    }
+
+    #[inline]
+    pub const fn from_millis(millis: u64) -> Duration {
+        Duration(millis * NANOS_PER_MILLI)
+    }
+
+    #[inline]
+    pub const fn from_micros(micros: u64) -> Duration {
+        Duration(micros * NANOS_PER_MICRO)
+    }
+
+    #[inline]
+    pub const fn from_nanos(nanos: u64) -> Duration {
+        Duration(nanos)
+    }
+
+    #[inline]
+    pub fn as_secs(&self) -> u64 {
+        self.0 / NANOS_PER_SEC
+    }
+
+    #[inline]
+    pub fn as_millis(&self) -> u64 {
+        self.0 / NANOS_PER_MILLI
+    }
+
+    #[inline]
+    pub fn as_micros(&self) -> u64 {
+        self.0 / NANOS_PER_MICRO
+    }
+
+    #[inline]
+    pub fn as_nanos(&self) -> u64 {
+        self.0
+    }
+
+    /// ミリ秒単位に切り上げて返す。タイムアウトが早く満了しないようにするために使う。
+    #[inline]
+    pub fn as_millis_ceil(&self) -> u64 {
+        self.0 / NANOS_PER_MILLI + if self.0 % NANOS_PER_MILLI != 0 { 1 } else { 0 }
+    }
+
+    #[inline]
+    pub fn is_zero(&self) -> bool {
+        self.0 == 0
+    }
+
+    #[inline]
+    pub fn checked_add(self, rhs: Duration) -> Option<Duration> {
+        self.0.checked_add(rhs.0).map(Duration)
+    }
+
+    #[inline]
+    pub fn checked_sub(self, rhs: Duration) -> Option<Duration> {
+        self.0.checked_sub(rhs.0).map(Duration)
+    }
+
+    #[inline]
+    pub fn saturating_add(self, rhs: Duration) -> Duration {
+        Duration(self.0.saturating_add(rhs.0))
+    }
+
+    #[inline]
+    pub fn saturating_sub(self, rhs: Duration) -> Duration {
+        Duration(self.0.saturating_sub(rhs.0))
+    }
+}
+
+impl Add for Duration {
+    type Output = Duration;
+
+    #[inline]
+    fn add(self, rhs: Duration) -> Duration {
+        self.checked_add(rhs).expect("overflow when adding durations")
+    }
+}
+
+impl AddAssign for Duration {
+    #[inline]
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub for Duration {
+    type Output = Duration;
+
+    #[inline]
+    fn sub(self, rhs: Duration) -> Duration {
+        self.checked_sub(rhs).expect("overflow when subtracting durations")
+    }
+}
+
+impl SubAssign for Duration {
+    #[inline]
+    fn sub_assign(&mut self, rhs: Duration) {
+        *self = *self - rhs;
+    }
+}
+
+impl Instant {
+    /// 現在の時刻を返す。
+    #[inline]
+    pub fn now() -> Instant {
+        Instant(interrupt::pit::nanos())
+    }
+
+    /// `earlier`からの経過時間を返す。`earlier`の方が後の場合は0を返す。
+    #[inline]
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        let diff = self.0.wrapping_sub(earlier.0) as i64;
+        if diff > 0 { Duration(diff as u64) } else { Duration::ZERO }
+    }
+
+    /// この時刻からの経過時間を返す。
+    #[inline]
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+}
+
+impl Ord for Instant {
+    #[inline]
+    fn cmp(&self, other: &Instant) -> Ordering {
+        (self.0.wrapping_sub(other.0) as i64).cmp(&0)
+    }
+}
+
+impl PartialOrd for Instant {
+    #[inline]
+    fn partial_cmp(&self, other: &Instant) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    #[inline]
+    fn add(self, rhs: Duration) -> Instant {
+        Instant(self.0.wrapping_add(rhs.0))
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    #[inline]
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    #[inline]
+    fn sub(self, rhs: Duration) -> Instant {
+        Instant(self.0.wrapping_sub(rhs.0))
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    #[inline]
+    fn sub_assign(&mut self, rhs: Duration) {
+        *self = *self - rhs;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    /// `duration_since`と同じ。
+    #[inline]
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+/// カレンダー上の日時。
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1から12。
+    pub month: u8,
+    /// 1から31。
+    pub day: u8,
+    /// 0から23。
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8
+}
+
+/// 実時間の時刻。UNIX時刻からの経過時間で保持する。
+///
+/// 起動時にリアルタイムクロックから読み込んだ時刻に単調増加する時刻の経過を足して求める。
+/// リアルタイムクロックが無い場合はUNIX時刻の起点から数える。
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Hash)]
+pub struct SystemTime(Duration);
+
+impl DateTime {
+    // 1970-01-01からの日数
+    // 閏年の計算を簡単にするため、3月始まりの400年周期で数える
+    fn days_from_epoch(&self) -> u64 {
+        let (year, month) = if self.month <= 2 {
+            (self.year as u64 - 1, self.month as u64 + 9)
+        } else {
+            (self.year as u64, self.month as u64 - 3)
+        };
+        let era = year / 400;
+        let year_of_era = year % 400;
+        let day_of_year = (153 * month + 2) / 5 + self.day as u64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        era * 146097 + day_of_era - 719468
+    }
+
+    // `days_from_epoch`の逆
+    fn from_days(days: u64) -> (u16, u8, u8) {
+        let days = days + 719468;
+        let era = days / 146097;
+        let day_of_era = days % 146097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let (year, month) = if month < 10 {
+            (era * 400 + year_of_era, month + 3)
+        } else {
+            (era * 400 + year_of_era + 1, month - 9)
+        };
+        (year as u16, month as u8, day as u8)
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}", self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+impl SystemTime {
+    /// 現在の時刻を返す。
+    #[inline]
+    pub fn now() -> SystemTime {
+        SystemTime(unsafe { boot_time } + Duration(Instant::now().0))
+    }
+
+    /// 日時から作る。1970年より前の日時は指定できない。
+    pub fn from_datetime(dt: &DateTime) -> SystemTime {
+        let secs = dt.days_from_epoch() * SECS_PER_DAY
+            + dt.hour as u64 * 60 * 60 + dt.minute as u64 * 60 + dt.second as u64;
+        SystemTime(Duration::from_secs(secs))
+    }
+
+    /// 日時に変換する。1秒未満は切り捨てる。
+    pub fn to_datetime(&self) -> DateTime {
+        let secs = self.0.as_secs();
+        let (year, month, day) = DateTime::from_days(secs / SECS_PER_DAY);
+        let secs = secs % SECS_PER_DAY;
+        DateTime {
+            year: year,
+            month: month,
+            day: day,
+            hour: (secs / (60 * 60)) as u8,
+            minute: (secs / 60 % 60) as u8,
+            second: (secs % 60) as u8
+        }
+    }
+
+    /// `earlier`からの経過時間を返す。`earlier`の方が後の場合は`None`を返す。
+    #[inline]
+    pub fn duration_since(&self, earlier: SystemTime) -> Option<Duration> {
+        self.0.checked_sub(earlier.0)
+    }
+
+    /// この時刻からの経過時間を返す。
+    #[inline]
+    pub fn elapsed(&self) -> Option<Duration> {
+        SystemTime::now().duration_since(*self)
+    }
+}
+
+impl Add<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    #[inline]
+    fn add(self, rhs: Duration) -> SystemTime {
+        SystemTime(self.0 + rhs)
+    }
+}
+
+impl Sub<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    #[inline]
+    fn sub(self, rhs: Duration) -> SystemTime {
+        SystemTime(self.0 - rhs)
+    }
+}
+
+/// リアルタイムクロックから起動時の時刻を求める。
+/// 割り込みの初期化の後に呼ぶ。
+pub fn init() {
+    if let Some(dt) = interrupt::rtc::read() {
+        let since_boot = Duration(Instant::now().0);
+        unsafe {
+            boot_time = SystemTime::from_datetime(&dt).0.saturating_sub(since_boot);
+        }
+    }
+}
+
+/// 現在の時刻を返す。
+#[inline]
+pub fn now() -> Instant {
+    Instant::now()
+}
+
+/// `duration`が経過するまでタスクを切り替えずに待つ。
+/// 割り込みハンドラや割り込みが禁止された状態でも使える、ハードウェアのための短い待機に使う。
+/// ただし、TSCの無いx86では時刻がタイマーの割り込みでしか進まないため、割り込みを禁止して呼んではならない。
+pub fn delay(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline { }
+}
+
+/// `micros`マイクロ秒が経過するまでタスクを切り替えずに待つ。
+#[inline]
+pub fn delay_us(micros: u64) {
+    delay(Duration::from_micros(micros));
+}
+
+/// `duration`が経過するまでタスクをブロックする。
+///
+/// 復帰はタイマーの割り込みの分解能に切り上げられる。
+/// より細かい精度が必要な場合は、CPUを譲らずに待つ`delay`を使う。
+#[inline]
+pub fn sleep(duration: Duration) {
+    task::sleep(duration);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cmp::Ordering;
+    use core::u64;
+
+    fn date(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+        DateTime {
+            year: year,
+            month: month,
+            day: day,
+            hour: hour,
+            minute: minute,
+            second: second
+        }
+    }
+
+    #[test]
+    fn test_days_from_epoch() {
+        assert_eq!(date(1970, 1, 1, 0, 0, 0).days_from_epoch(), 0);
+        assert_eq!(date(2000, 2, 29, 0, 0, 0).days_from_epoch(), 11016);
+        assert_eq!(date(2100, 2, 28, 0, 0, 0).days_from_epoch(), 47540);
+        // 2100年は閏年ではない
+        assert_eq!(date(2100, 3, 1, 0, 0, 0).days_from_epoch(), 47541);
+    }
+
+    #[test]
+    fn test_from_days() {
+        assert_eq!(DateTime::from_days(0), (1970, 1, 1));
+        assert_eq!(DateTime::from_days(11016), (2000, 2, 29));
+        assert_eq!(DateTime::from_days(11017), (2000, 3, 1));
+        assert_eq!(DateTime::from_days(47541), (2100, 3, 1));
+    }
+
+    #[test]
+    fn test_system_time_round_trip() {
+        assert_eq!(SystemTime::from_datetime(&date(1970, 1, 1, 0, 0, 0)), UNIX_EPOCH);
+        assert_eq!(SystemTime::from_datetime(&date(2038, 1, 19, 3, 14, 8)).duration_since(UNIX_EPOCH),
+                   Some(Duration::from_secs(1 << 31)));
+
+        let dates = [
+            date(1970, 1, 1, 0, 0, 0),
+            date(2000, 2, 29, 23, 59, 59),
+            date(2100, 3, 1, 12, 30, 0),
+            date(2016, 12, 31, 0, 0, 1)
+        ];
+        for dt in dates.iter() {
+            assert_eq!(SystemTime::from_datetime(dt).to_datetime(), *dt);
+        }
+    }
+
+    #[test]
+    fn test_instant_ordering_across_wraparound() {
+        let before = Instant(u64::MAX - 5);
+        let after = before + Duration::from_nanos(10);
+        assert_eq!(after, Instant(4));
+
+        assert!(before < after);
+        assert!(after > before);
+        assert_eq!(before.cmp(&before), Ordering::Equal);
+        assert_eq!(after - Duration::from_nanos(10), before);
+    }
+
+    #[test]
+    fn test_instant_duration_since_across_wraparound() {
+        let before = Instant(u64::MAX - 5);
+        let after = Instant(4);
+        assert_eq!(after.duration_since(before), Duration::from_nanos(10));
+        assert_eq!(after - before, Duration::from_nanos(10));
+        // 順序が逆ならば0になる
+        assert_eq!(before.duration_since(after), Duration::ZERO);
+    }
+}
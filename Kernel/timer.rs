@@ -1,23 +1,57 @@
-use rt::{Force, ForceRef, IntBlocker};
-use lists::{DList, SortedList};
-use event::{Event, EventQueue};
-use core::cmp::Ordering;
-use core::iter::FromIterator;
-use core::ptr::Shared;
-
-pub type TimerId = u16;
+use rt::{Force, ForceRef};
+use arch::interrupt;
+#[cfg(tickless)]
+use arch::smp;
+use lists::DList;
+use sync::SpinLock;
+use event;
+use task;
+use time::{Duration, Instant};
+use memory;
+use memory::kcache::{KCacheAllocator, KCBox};
+use core::cmp;
+use core::mem;
+use core::usize;
+use core::ptr::{self, Shared};
+use alloc::boxed::Box;
+
+pub type TimerId = usize;
 
 pub enum TimerHandler {
     Unset,
-    Queue(ForceRef<EventQueue>),
-    Callback(fn(TimerId) -> ())
+    Event,
+    Callback(fn(TimerId) -> ()),
+    /// 作成時に渡した値を引数に呼ぶ。
+    Context(fn(usize) -> (), usize),
+    Closure(Box<FnMut() + Send>)
 }
 
+// タイマーホイールの各段のスロット数のビット数
+const WHEEL_BITS: usize = 6;
+const WHEEL_SLOTS: usize = 1 << WHEEL_BITS;
+const WHEEL_MASK: u64 = WHEEL_SLOTS as u64 - 1;
+// 段の数。1段目は1ミリ秒、以降は前の段の`WHEEL_SLOTS`倍の間隔でスロットが並ぶ
+const WHEEL_LEVELS: usize = 6;
+// ホイールで表せる最大の時間。これより先のタイマーは最上段に置き、巡ってくるたびに置き直す
+const MAX_DELTA: u64 = (1 << (WHEEL_BITS * WHEEL_LEVELS)) - 1;
+// ホイールに置かれていないタイマーの`slot`
+const NO_SLOT: usize = usize::MAX;
+
+// `TimerManager`とタイマーの状態を保護する
+// ハンドラはタスクを切り替える場合があるので、このロックを解除してから呼ぶ
+static LOCK: SpinLock<()> = SpinLock::new(());
+
 pub struct TimerManager {
-    timer_pool: [TimerEntity; 256],
     free_timers: DList<TimerEntity>,
-    ticking_timers: SortedList<TimerEntity>,
-    counter: usize
+    // 階層化したタイマーホイール
+    // 設定、解除、満了はいずれもならしO(1)で行える
+    wheel: [DList<TimerEntity>; WHEEL_SLOTS * WHEEL_LEVELS],
+    // ホイール上で満了の処理が済んだ時刻
+    processed: u64,
+    // 32ビット環境でもミリ秒単位で一周しない
+    counter: u64,
+    next_id: TimerId,
+    kcache: KCacheAllocator<TimerEntity>
 }
 
 unsafe impl Send for TimerManager { }
@@ -26,60 +60,276 @@ unsafe impl Sync for TimerManager { }
 impl TimerManager {
     #[inline(always)]
     pub fn init(&mut self) {
-        for (i, timer) in self.timer_pool.iter_mut().enumerate() {
-            *timer = TimerEntity::new(i as TimerId);
+        let kcache = memory::check_oom_opt(KCacheAllocator::new("Timer", mem::align_of::<TimerEntity>(), None));
+        unsafe {
+            let mut wheel: [DList<TimerEntity>; WHEEL_SLOTS * WHEEL_LEVELS] = mem::uninitialized();
+            for slot in wheel.iter_mut() {
+                ptr::write(slot, DList::new());
+            }
+
+            ptr::write(self, TimerManager {
+                free_timers: DList::new(),
+                wheel: wheel,
+                processed: 0,
+                counter: 0,
+                next_id: 0,
+                kcache: kcache
+            });
         }
-        self.free_timers = DList::from_iter(self.timer_pool.iter_mut().map(|timer| unsafe { Shared::new(timer) }));
-        self.ticking_timers = SortedList::new(TimerEntity::cmp);
-        self.counter = 0;
     }
 
-    fn with_handler(&mut self, handler: TimerHandler) -> TimerId {
+    fn with_handler(&mut self, handler: TimerHandler) -> Shared<TimerEntity> {
+        let _guard = LOCK.lock();
+
         unsafe {
-            let timer = self.free_timers.pop_front().expect("Not enough timers");
+            // 空きが無ければプールを広げる
+            let timer = self.free_timers.pop_front().unwrap_or_else(|| {
+                let id = self.next_id;
+                self.next_id += 1;
+                let b = memory::check_oom_opt(KCBox::new(self.kcache.clone(), TimerEntity::new(id)));
+                Shared::new(KCBox::into_raw(b))
+            });
             (**timer).handler = handler;
-            (**timer).id
+            timer
         }
     }
 
     fn remove(&mut self, timer: Shared<TimerEntity>) {
-        if self.ticking_timers.contains(&timer) {
-            self.ticking_timers.remove(&timer);
+        let _guard = LOCK.lock();
+
+        self.disarm(timer);
+        unsafe {
+            if (**timer).running {
+                // ハンドラを呼び終えた`tick`が解放する
+                (**timer).removed = true;
+                (**timer).interval = 0;
+                return;
+            }
+        }
+        self.release(timer);
+    }
+
+    // タイマーを初期状態に戻してプールに返す。`LOCK`を保持した状態で呼ぶ
+    fn release(&mut self, timer: Shared<TimerEntity>) {
+        unsafe {
+            // クロージャが持っている値を解放する
+            (**timer).handler = TimerHandler::Unset;
+            (**timer).interval = 0;
+            (**timer).overruns = 0;
+            (**timer).removed = false;
         }
         self.free_timers.push_back(timer);
     }
 
-    pub fn tick(&mut self, count: usize) {
+    // `tick`の時刻に満了するようにホイールに置く
+    fn arm(&mut self, timer: Shared<TimerEntity>) {
         unsafe {
-            self.counter = self.counter.wrapping_add(count);
+            let delta = (**timer).tick.wrapping_sub(self.processed) as i64;
+            let expires = if delta <= 0 {
+                // 既に過ぎていれば現在のスロットに置き、次の処理で満了させる
+                self.processed
+            } else if delta as u64 > MAX_DELTA {
+                self.processed + MAX_DELTA
+            } else {
+                (**timer).tick
+            };
+
+            let delta = expires - self.processed;
+            let mut level = 0;
+            while level + 1 < WHEEL_LEVELS && delta >> (WHEEL_BITS * (level + 1)) != 0 {
+                level += 1;
+            }
 
-            while let Some(timer) = self.ticking_timers.front() {
-                if (**timer).tick > self.counter {
+            let slot = TimerManager::slot(level, expires);
+            (**timer).slot = slot;
+            self.wheel[slot].push_back(timer);
+        }
+    }
+
+    // ホイールに置かれていれば取り除く
+    fn disarm(&mut self, timer: Shared<TimerEntity>) {
+        unsafe {
+            let slot = (**timer).slot;
+            if slot != NO_SLOT {
+                self.wheel[slot].remove(&timer);
+                (**timer).slot = NO_SLOT;
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn slot(level: usize, time: u64) -> usize {
+        level * WHEEL_SLOTS + ((time >> (WHEEL_BITS * level)) & WHEEL_MASK) as usize
+    }
+
+    // `processed`から`limit`以内で、空でないスロットに到達する最も早い時刻を返す
+    fn next_event(&self, limit: u64) -> Option<u64> {
+        let mut next: Option<u64> = None;
+
+        for level in 0..WHEEL_LEVELS {
+            let shift = WHEEL_BITS * level;
+            let base = self.processed >> shift;
+            for k in 1..(WHEEL_SLOTS as u64 + 1) {
+                let time = (base + k) << shift;
+                if time - self.processed > limit || next.map_or(false, |next| time >= next) {
+                    break;
+                }
+                if !self.wheel[TimerManager::slot(level, time)].is_empty() {
+                    next = Some(time);
                     break;
                 }
-                self.ticking_timers.remove(&timer);
+            }
+        }
+
+        next
+    }
+
+    // `processed`の時刻に到達した上段のスロットのタイマーを置き直す
+    fn cascade(&mut self) {
+        for level in 1..WHEEL_LEVELS {
+            let shift = WHEEL_BITS * level;
+            if self.processed & ((1 << shift) - 1) != 0 {
+                break;
+            }
+
+            let slot = TimerManager::slot(level, self.processed);
+            let mut list = mem::replace(&mut self.wheel[slot], DList::new());
+            while let Some(timer) = list.pop_front() {
+                self.arm(timer);
+            }
+        }
+    }
+
+    /// `count`ミリ秒の経過を計上し、満了したタイマーを処理する。
+    /// 最初のプロセッサのタイマー割り込みから呼ばれる。
+    pub fn tick(&mut self, count: usize) {
+        unsafe {
+            let mut guard = LOCK.lock();
+            self.counter = self.counter.wrapping_add(count as u64);
+
+            loop {
+                let slot = TimerManager::slot(0, self.processed);
+                let timer = match self.wheel[slot].pop_front() {
+                    Some(timer) => timer,
+                    None => {
+                        if !is_after(self.counter, self.processed) {
+                            break;
+                        }
+                        // 空のスロットは飛ばして進める
+                        let limit = self.counter - self.processed;
+                        self.processed = self.next_event(limit).unwrap_or(self.counter);
+                        self.cascade();
+                        continue;
+                    }
+                };
+
+                (**timer).slot = NO_SLOT;
+                if (**timer).interval > 0 {
+                    self.reload(timer);
+                }
+                let id = (**timer).id;
+                // ハンドラの中でタスクを切り替えると残りのタイマーの処理が遅れるので、
+                // スケジューラのタイマーは切り替えを要求するだけにして、最後に`task::switch_pending`で切り替える
                 match (**timer).handler {
                     TimerHandler::Unset => unreachable!(),
-                    TimerHandler::Queue(ref mut queue) => queue.push(Event::Timer((**timer).id)),
+                    TimerHandler::Event => {
+                        drop(guard);
+                        if !event::post_timer(id) && (**timer).interval > 0 {
+                            // 前回の満了のイベントがまだ処理されていないので、この周期も間に合わなかった
+                            let _guard = LOCK.lock();
+                            (**timer).overruns += 1;
+                        }
+                    },
                     TimerHandler::Callback(cb) => {
-                        cb((**timer).id);
-                        break;
+                        drop(guard);
+                        cb(id);
+                    },
+                    TimerHandler::Context(cb, context) => {
+                        drop(guard);
+                        cb(context);
+                    },
+                    TimerHandler::Closure(_) => {
+                        // 呼んでいる間に他のプロセッサで破棄されても解放されないように、ハンドラを取り出しておく
+                        let mut handler = mem::replace(&mut (**timer).handler, TimerHandler::Unset);
+                        (**timer).running = true;
+                        drop(guard);
+                        if let TimerHandler::Closure(ref mut f) = handler {
+                            f();
+                        }
+
+                        guard = LOCK.lock();
+                        (**timer).running = false;
+                        if (**timer).removed {
+                            self.release(timer);
+                        } else {
+                            (**timer).handler = handler;
+                        }
+                        continue;
                     }
                 }
+                guard = LOCK.lock();
             }
+
+            drop(guard);
         }
+
+        task::switch_pending();
     }
 
+    // 周期タイマーを前回の満了時刻を基準に再設定し、満了の処理が遅れて飛ばした周期を数える
+    // イベントを送るタイマーでは、前回のイベントが取り出される前に満了した周期も`tick`で数える
+    unsafe fn reload(&mut self, timer: Shared<TimerEntity>) {
+        let interval = (**timer).interval;
+        let late = self.counter.wrapping_sub((**timer).tick);
+        let missed = late / interval;
+        (**timer).overruns += missed as usize;
+        (**timer).tick = (**timer).tick.wrapping_add((missed + 1) * interval);
+        self.arm(timer);
+    }
+
+    /// 起動時からの経過時間をミリ秒単位で返す。
     #[inline(always)]
-    pub fn counter(&self) -> usize {
+    pub fn counter(&self) -> u64 {
+        // 32ビット環境では読み込みの途中で更新されないようにする
+        let _guard = LOCK.lock();
         self.counter
     }
+
+    /// 次にタイマーの処理が必要になるまでの時間を返す。
+    /// 動作中のタイマーが無い場合は`None`を返す。
+    ///
+    /// 上段のスロットは置き直す時刻を返すため、実際の満了より早い場合がある。
+    pub fn next_deadline(&mut self) -> Option<Duration> {
+        let _guard = LOCK.lock();
+
+        let next = if self.wheel[TimerManager::slot(0, self.processed)].is_empty() {
+            self.next_event(MAX_DELTA + 1)
+        } else {
+            Some(self.processed)
+        };
+
+        let counter = self.counter;
+        next.map(|next| {
+            let remaining = cmp::max(next.wrapping_sub(counter) as i64, 0) as u64;
+            Duration::from_millis(remaining)
+        })
+    }
 }
 
 struct TimerEntity {
     id: TimerId,
     handler: TimerHandler,
-    tick: usize,
+    tick: u64,
+    // 置かれているホイールのスロット
+    slot: usize,
+    // 周期タイマーの間隔。0の場合は1回だけ満了する
+    interval: u64,
+    // 周期タイマーで処理が間に合わず飛ばされた周期の数
+    overruns: usize,
+    // `tick`が取り出したクロージャを呼んでいる
+    running: bool,
+    // `running`の間に破棄された
+    removed: bool,
     prev: Option<Shared<TimerEntity>>,
     next: Option<Shared<TimerEntity>>
 }
@@ -93,50 +343,70 @@ impl TimerEntity {
             id: id,
             handler: TimerHandler::Unset,
             tick: 0,
+            slot: NO_SLOT,
+            interval: 0,
+            overruns: 0,
+            running: false,
+            removed: false,
             prev: None,
             next: None
         }
     }
 
-    pub fn reset(this: Shared<TimerEntity>, delay: usize) {
+    pub fn reset(this: Shared<TimerEntity>, delay: Duration) {
+        // 指定した時間より早く満了しないように切り上げる
+        TimerEntity::start(this, delay.as_millis_ceil(), 0);
+    }
+
+    pub fn reset_at(this: Shared<TimerEntity>, deadline: Instant) {
+        // 時刻とタイマーのカウンタは別の時計なので、残り時間に直して設定する
+        TimerEntity::reset(this, deadline - Instant::now());
+    }
+
+    pub fn periodic(this: Shared<TimerEntity>, interval: Duration) {
+        let interval = interval.as_millis_ceil();
+        assert!(interval > 0, "The interval of a periodic timer must be positive");
+
+        TimerEntity::start(this, interval, interval);
+    }
+
+    // 現在から`delay`ミリ秒後に満了するように置く
+    fn start(this: Shared<TimerEntity>, delay: u64, interval: u64) {
         unsafe {
-            let _blocker = IntBlocker::new();
+            let _guard = LOCK.lock();
 
             let mut man = manager();
-            let counter = man.counter();
-            if counter < (**this).tick {
-                // リストの最後に移動
-                man.ticking_timers.remove(&this);
-            }
+            man.disarm(this);
 
-            (**this).tick = counter + delay;
-            man.ticking_timers.push(this);
+            (**this).tick = man.counter.wrapping_add(delay);
+            (**this).interval = interval;
+            (**this).overruns = 0;
+            man.arm(this);
         }
     }
 
     pub fn clear(this: Shared<TimerEntity>) {
         unsafe {
-            let _blocker = IntBlocker::new();
+            let _guard = LOCK.lock();
 
-            let mut man = manager();
-            if man.ticking_timers.contains(&this) {
-                man.ticking_timers.remove(&this);
-            }
-            (**this).tick = 0;
+            manager().disarm(this);
+            (**this).interval = 0;
         }
     }
 
-    fn cmp(a: &TimerEntity, b: &TimerEntity) -> Ordering {
-        a.tick.cmp(&b.tick)
+    fn take_overruns(this: Shared<TimerEntity>) -> usize {
+        let _guard = LOCK.lock();
+        unsafe { mem::replace(&mut (**this).overruns, 0) }
     }
 }
 
-pub struct Timer(TimerId);
+pub struct Timer(Shared<TimerEntity>);
 
 impl Timer {
+    /// 満了すると`Event::Timer`を購読しているタスクに送るタイマーを作る。
     #[inline]
-    pub fn with_queue(queue: ForceRef<EventQueue>) -> Timer {
-        Timer(manager().with_handler(TimerHandler::Queue(queue)))
+    pub fn with_event() -> Timer {
+        Timer(manager().with_handler(TimerHandler::Event))
     }
 
     #[inline]
@@ -144,23 +414,68 @@ impl Timer {
         Timer(manager().with_handler(TimerHandler::Callback(callback)))
     }
 
+    /// 満了すると`callback`に`context`を渡して呼ぶタイマーを作る。
     #[inline]
-    fn entity(&self) -> Shared<TimerEntity> {
+    pub fn with_context(callback: fn(usize) -> (), context: usize) -> Timer {
+        Timer(manager().with_handler(TimerHandler::Context(callback, context)))
+    }
+
+    /// 満了すると`f`を呼ぶタイマーを作る。
+    ///
+    /// `f`は割り込みハンドラから呼ばれる。`f`が呼ばれている間に破棄した場合は、呼び終えてから解放される。
+    #[inline]
+    pub fn with_closure<F: FnMut() + Send + 'static>(f: F) -> Timer {
+        Timer(manager().with_handler(TimerHandler::Closure(Box::new(f))))
+    }
+
+    /// `with_context`で作ったタイマーに渡す値を変更する。
+    pub fn set_context(&self, context: usize) {
+        let _guard = LOCK.lock();
         unsafe {
-            Shared::new(&mut manager().timer_pool[self.0 as usize])
+            if let TimerHandler::Context(_, ref mut c) = (**self.0).handler {
+                *c = context;
+            }
         }
     }
 
     #[inline(always)]
-    pub fn id(&self) -> TimerId {
+    fn entity(&self) -> Shared<TimerEntity> {
         self.0
     }
 
     #[inline(always)]
-    pub fn reset(&self, delay: usize) {
+    pub fn id(&self) -> TimerId {
+        unsafe { (**self.0).id }
+    }
+
+    /// `delay`が経過した後に満了するように設定する。
+    #[inline(always)]
+    pub fn reset(&self, delay: Duration) {
         TimerEntity::reset(self.entity(), delay);
     }
 
+    /// `deadline`の時刻に満了するように設定する。
+    #[inline(always)]
+    pub fn reset_at(&self, deadline: Instant) {
+        TimerEntity::reset_at(self.entity(), deadline);
+    }
+
+    /// `interval`ごとに満了する周期タイマーとして開始する。
+    ///
+    /// 次の満了時刻は処理した時刻ではなく前回の満了時刻から決めるため、ずれが蓄積しない。
+    /// 処理が間に合わずに過ぎた周期は飛ばされ、`take_overruns`で数を得られる。
+    /// イベントを送るタイマーでは、前回のイベントが取り出される前に満了した周期も数える。
+    #[inline(always)]
+    pub fn periodic(&self, interval: Duration) {
+        TimerEntity::periodic(self.entity(), interval);
+    }
+
+    /// 前回の呼び出し以降に飛ばされた周期の数を返す。
+    #[inline(always)]
+    pub fn take_overruns(&self) -> usize {
+        TimerEntity::take_overruns(self.entity())
+    }
+
     #[inline(always)]
     pub fn clear(&self) {
         TimerEntity::clear(self.entity());
@@ -174,12 +489,12 @@ impl Drop for Timer {
     }
 }
 
-pub struct UnmanagedTimer(TimerId);
+pub struct UnmanagedTimer(Shared<TimerEntity>);
 
 impl UnmanagedTimer {
     #[inline]
-    pub unsafe fn with_queue(queue: ForceRef<EventQueue>) -> UnmanagedTimer {
-        UnmanagedTimer(manager().with_handler(TimerHandler::Queue(queue)))
+    pub unsafe fn with_event() -> UnmanagedTimer {
+        UnmanagedTimer(manager().with_handler(TimerHandler::Event))
     }
 
     #[inline]
@@ -188,22 +503,48 @@ impl UnmanagedTimer {
     }
 
     #[inline]
+    pub unsafe fn with_context(callback: fn(usize) -> (), context: usize) -> UnmanagedTimer {
+        UnmanagedTimer(manager().with_handler(TimerHandler::Context(callback, context)))
+    }
+
+    #[inline(always)]
     fn entity(&self) -> Shared<TimerEntity> {
-        unsafe {
-            Shared::new(&mut manager().timer_pool[self.0 as usize])
-        }
+        self.0
     }
 
     #[inline(always)]
     pub fn id(&self) -> TimerId {
-        self.0
+        unsafe { (**self.0).id }
     }
 
+    /// `delay`が経過した後に満了するように設定する。
     #[inline(always)]
-    pub fn reset(&self, delay: usize) {
+    pub fn reset(&self, delay: Duration) {
         TimerEntity::reset(self.entity(), delay);
     }
 
+    /// `deadline`の時刻に満了するように設定する。
+    #[inline(always)]
+    pub fn reset_at(&self, deadline: Instant) {
+        TimerEntity::reset_at(self.entity(), deadline);
+    }
+
+    /// `interval`ごとに満了する周期タイマーとして開始する。
+    ///
+    /// 次の満了時刻は処理した時刻ではなく前回の満了時刻から決めるため、ずれが蓄積しない。
+    /// 処理が間に合わずに過ぎた周期は飛ばされ、`take_overruns`で数を得られる。
+    /// イベントを送るタイマーでは、前回のイベントが取り出される前に満了した周期も数える。
+    #[inline(always)]
+    pub fn periodic(&self, interval: Duration) {
+        TimerEntity::periodic(self.entity(), interval);
+    }
+
+    /// 前回の呼び出し以降に飛ばされた周期の数を返す。
+    #[inline(always)]
+    pub fn take_overruns(&self) -> usize {
+        TimerEntity::take_overruns(self.entity())
+    }
+
     #[inline(always)]
     pub fn clear(&self) {
         TimerEntity::clear(self.entity());
@@ -224,6 +565,12 @@ impl Clone for UnmanagedTimer {
 
 static MANAGER: Force<TimerManager> = Force::new();
 
+// `a`が`b`より後の時刻ならば`true`を返す。カウンタが一周しても正しく判定できる
+#[inline(always)]
+fn is_after(a: u64, b: u64) -> bool {
+    (a.wrapping_sub(b) as i64) > 0
+}
+
 #[inline]
 pub fn init() {
     MANAGER.setup().init();
@@ -234,3 +581,51 @@ pub fn manager() -> ForceRef<TimerManager> {
     MANAGER.as_ref()
 }
 
+/// 割り込みが発生するまでCPUを休止する。
+///
+/// 休止中は周期的な割り込みを止め、次のタイマーが満了する時刻にのみ割り込みを発生させる。
+/// 割り込みが禁止された状態で呼ぶ必要があり、復帰時も割り込みは禁止されている。
+#[cfg(tickless)]
+pub fn idle() {
+    // ティックを発生させているのは最初のプロセッサだけなので、他のプロセッサは単に休止する
+    if smp::cpu_id() != 0 {
+        interrupt::enable_wait();
+        interrupt::disable();
+        return;
+    }
+
+    // 早めに起きる分には問題ないので切り捨てる
+    let delay = manager().next_deadline().map(|delay| cmp::min(delay.as_millis(), usize::MAX as u64) as usize);
+    interrupt::pit::stop_tick(delay);
+
+    interrupt::enable_wait();
+    interrupt::disable();
+
+    interrupt::pit::restart_tick();
+}
+
+/// 割り込みが発生するまでCPUを休止する。
+///
+/// 割り込みが禁止された状態で呼ぶ必要があり、復帰時も割り込みは禁止されている。
+#[cfg(not(tickless))]
+pub fn idle() {
+    interrupt::enable_wait();
+    interrupt::disable();
+}
+
+/// `idle`で止めた周期的な割り込みを再開する。止めていなければ何もしない。
+///
+/// 休止中の割り込みハンドラからタスクが切り替わると`idle`に戻るまでティックが止まったままになるので、
+/// タスクを切り替える前にスケジューラから呼ぶ。割り込みが禁止された状態で呼ぶ必要がある。
+#[cfg(tickless)]
+pub fn resume_tick() {
+    if smp::cpu_id() == 0 {
+        interrupt::pit::restart_tick();
+    }
+}
+
+/// `idle`で止めた周期的な割り込みを再開する。止めていなければ何もしない。
+#[cfg(not(tickless))]
+#[inline(always)]
+pub fn resume_tick() {}
+